@@ -0,0 +1,186 @@
+// (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
+
+//! Key-agreement-agnostic node identities.
+//!
+//! Every identity, legacy or modern, ultimately yields a 40-bit node address
+//! and something `Switch` can use to agree on a shared secret with a peer.
+//! Two backends are supported side by side so the addressing layer doesn't
+//! need to know or care which one a given peer uses:
+//!
+//! - [`IdentityType::Legacy`]: Curve25519 for key agreement, Ed25519 for
+//!   signatures, the original ZeroTier identity type. The address is subject
+//!   to a proof-of-work collision check so that addresses can't be cheaply
+//!   forged to collide with an existing one.
+//! - [`IdentityType::P384`]: NIST P-384 for both ECDH and ECDSA, used
+//!   alongside the legacy type for higher assurance deployments.
+
+use p384::ecdsa::VerifyingKey as P384VerifyingKey;
+use p384::PublicKey as P384PublicKey;
+use sha2::{Digest, Sha512};
+use x25519_dalek::PublicKey as X25519PublicKey;
+
+use hkdf::Hkdf;
+
+/// A 40-bit ZeroTier node address, derived from an identity's public key(s).
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct Address(pub u64);
+
+impl Address {
+    const MASK: u64 = 0xff_ffff_ffff;
+
+    fn from_hash(hash: &[u8]) -> Self {
+        let mut bytes = [0u8; 8];
+        bytes[3..8].copy_from_slice(&hash[0..5]);
+        Address(u64::from_be_bytes(bytes) & Self::MASK)
+    }
+}
+
+/// Which key-agreement/signature suite an [`Identity`] uses. Higher numeric
+/// values are preferred when two peers negotiate a mutually supported suite.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug)]
+#[repr(u8)]
+pub enum IdentityType {
+    Legacy = 0,
+    P384 = 1,
+}
+
+/// A node identity: a public key (or pair of keys, for signing) plus the
+/// address derived from it.
+pub enum Identity {
+    Legacy { x25519_public: X25519PublicKey, address: Address, pow_nonce: u64 },
+    P384 { ecdh_public: P384PublicKey, ecdsa_public: P384VerifyingKey, address: Address },
+}
+
+impl Identity {
+    pub fn identity_type(&self) -> IdentityType {
+        match self {
+            Identity::Legacy { .. } => IdentityType::Legacy,
+            Identity::P384 { .. } => IdentityType::P384,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        match self {
+            Identity::Legacy { address, .. } => *address,
+            Identity::P384 { address, .. } => *address,
+        }
+    }
+
+    /// Derive a legacy identity's address from its x25519 public key and a
+    /// proof-of-work nonce, requiring (as the original ZeroTier identity
+    /// generation does) that hashing the public key together with the nonce
+    /// produce a digest whose first byte is below `POW_DIFFICULTY` threshold,
+    /// making addresses expensive to mint and therefore expensive to collide.
+    pub fn new_legacy(x25519_public: X25519PublicKey, pow_nonce: u64) -> Option<Self> {
+        let hash = legacy_pow_hash(x25519_public.as_bytes(), pow_nonce);
+        if hash[0] >= POW_DIFFICULTY {
+            return None;
+        }
+        Some(Self::Legacy { x25519_public, address: Address::from_hash(&hash), pow_nonce })
+    }
+
+    pub fn new_p384(ecdh_public: P384PublicKey, ecdsa_public: P384VerifyingKey) -> Self {
+        let mut hasher = Sha512::new();
+        hasher.update(ecdh_public.to_sec1_bytes());
+        hasher.update(ecdsa_public.to_sec1_bytes());
+        let hash = hasher.finalize();
+        Self::P384 { ecdh_public, ecdsa_public, address: Address::from_hash(&hash) }
+    }
+}
+
+/// Addresses must hash below this value (out of 0xff) for a legacy identity
+/// to be accepted, bounding how cheaply an address can be minted.
+const POW_DIFFICULTY: u8 = 0x07;
+
+fn legacy_pow_hash(public_key: &[u8; 32], nonce: u64) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    hasher.update(public_key);
+    hasher.update(nonce.to_be_bytes());
+    hasher.finalize().into()
+}
+
+/// Choose the strongest suite two peers both support, falling back to the
+/// legacy suite when the peer has no modern identity.
+pub fn negotiate(mine: &[IdentityType], theirs: &[IdentityType]) -> IdentityType {
+    mine.iter().filter(|t| theirs.contains(t)).copied().max().unwrap_or(IdentityType::Legacy)
+}
+
+/// Derive session key material from one or both of a peer pair's shared
+/// secrets. When both identity types are present for both peers, the two DH
+/// outputs are concatenated before being run through HKDF so that breaking
+/// either curve alone is not sufficient to recover the traffic key.
+pub fn combine_shared_secrets(x25519_secret: Option<&[u8; 32]>, p384_secret: Option<&[u8; 48]>) -> [u8; 64] {
+    let mut ikm = Vec::with_capacity(32 + 48);
+    if let Some(s) = x25519_secret {
+        ikm.extend_from_slice(s);
+    }
+    if let Some(s) = p384_secret {
+        ikm.extend_from_slice(s);
+    }
+    let hk = Hkdf::<Sha512>::new(None, &ikm);
+    let mut out = [0u8; 64];
+    hk.expand(b"zt-identity-combine", &mut out).expect("64 bytes is a valid HKDF output length");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use p384::ecdsa::SigningKey;
+    use p384::SecretKey as P384SecretKey;
+
+    use super::*;
+
+    fn legacy_identity() -> Identity {
+        let x25519_public = X25519PublicKey::from(x25519_dalek::StaticSecret::random_from_rng(rand_core::OsRng));
+        (0u64..).find_map(|nonce| Identity::new_legacy(x25519_public, nonce)).expect("a passing nonce exists within a small search")
+    }
+
+    #[test]
+    fn new_legacy_rejects_nonces_that_fail_the_pow_check() {
+        let x25519_public = X25519PublicKey::from(x25519_dalek::StaticSecret::random_from_rng(rand_core::OsRng));
+        // Across all nonces, some pass and some fail -- if every nonce passed
+        // the difficulty check would be meaningless.
+        assert!((0u64..1000).any(|nonce| Identity::new_legacy(x25519_public, nonce).is_none()));
+    }
+
+    #[test]
+    fn new_legacy_address_is_stable_for_the_same_key_and_nonce() {
+        let identity = legacy_identity();
+        let Identity::Legacy { x25519_public, pow_nonce, .. } = identity else {
+            panic!("legacy_identity() always returns a Legacy variant");
+        };
+        assert_eq!(Identity::new_legacy(x25519_public, pow_nonce).unwrap().address(), identity.address());
+    }
+
+    #[test]
+    fn new_p384_derives_its_address_from_both_keys() {
+        let ecdh_public = P384SecretKey::random(&mut rand_core::OsRng).public_key();
+        let ecdsa_public = *SigningKey::random(&mut rand_core::OsRng).verifying_key();
+        let identity = Identity::new_p384(ecdh_public, ecdsa_public);
+        assert_eq!(identity.identity_type(), IdentityType::P384);
+
+        // Changing either key changes the derived address: it isn't only a
+        // function of the ECDH key (or only the ECDSA key).
+        let other_ecdsa_public = *SigningKey::random(&mut rand_core::OsRng).verifying_key();
+        assert_ne!(Identity::new_p384(ecdh_public, other_ecdsa_public).address(), identity.address());
+        let other_ecdh_public = P384SecretKey::random(&mut rand_core::OsRng).public_key();
+        assert_ne!(Identity::new_p384(other_ecdh_public, ecdsa_public).address(), identity.address());
+    }
+
+    #[test]
+    fn negotiate_prefers_the_highest_mutually_supported_suite() {
+        assert_eq!(negotiate(&[IdentityType::Legacy, IdentityType::P384], &[IdentityType::Legacy, IdentityType::P384]), IdentityType::P384);
+        assert_eq!(negotiate(&[IdentityType::Legacy, IdentityType::P384], &[IdentityType::Legacy]), IdentityType::Legacy);
+        assert_eq!(negotiate(&[IdentityType::Legacy], &[IdentityType::Legacy, IdentityType::P384]), IdentityType::Legacy);
+    }
+
+    #[test]
+    fn combine_shared_secrets_is_sensitive_to_every_input() {
+        let x25519_secret = [1u8; 32];
+        let other_x25519_secret = [2u8; 32];
+        let p384_secret = [3u8; 48];
+
+        assert_ne!(combine_shared_secrets(Some(&x25519_secret), None), combine_shared_secrets(Some(&other_x25519_secret), None));
+        assert_ne!(combine_shared_secrets(Some(&x25519_secret), None), combine_shared_secrets(Some(&x25519_secret), Some(&p384_secret)));
+    }
+}
@@ -0,0 +1,206 @@
+// (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
+
+//! Parsing of IGMPv2/v3 (IPv4) and MLD (ICMPv6) multicast membership messages,
+//! used by `Switch` to snoop group joins/leaves and auto-populate
+//! `MulticastGroup` subscriptions without any explicit configuration.
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+const IPPROTO_IGMP: u8 = 2;
+const IPPROTO_ICMPV6: u8 = 58;
+
+const IGMP_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+const IGMP_V3_MEMBERSHIP_REPORT: u8 = 0x22;
+const IGMP_V2_LEAVE_GROUP: u8 = 0x17;
+
+const MLD_LISTENER_REPORT: u8 = 130;
+const MLD_LISTENER_REPORT_V1: u8 = 131;
+const MLD_LISTENER_REDUCTION: u8 = 132;
+const MLDV2_LISTENER_REPORT: u8 = 143;
+
+/// An observed change in group membership snooped from a passing frame.
+pub(crate) enum MembershipEvent {
+    /// The sender wants to join/renew `group_ip` (a 128-bit IPv6 address, or
+    /// a 32-bit IPv4 address zero-extended into the low bits of the u128).
+    Join { group_ip: u128 },
+    Leave { group_ip: u128 },
+}
+
+/// Snoop an Ethernet frame for IGMP/MLD membership messages.
+///
+/// `ethertype` and `payload` are the Ethernet frame's type field and the bytes
+/// following it (i.e. the IP packet, if any). Returns every membership change
+/// found; IGMPv3/MLDv2 reports may carry more than one group record.
+pub(crate) fn snoop(ethertype: u16, payload: &[u8]) -> Vec<MembershipEvent> {
+    match ethertype {
+        ETHERTYPE_IPV4 => snoop_igmp(payload),
+        ETHERTYPE_IPV6 => snoop_mld(payload),
+        _ => Vec::new(),
+    }
+}
+
+fn snoop_igmp(ip_packet: &[u8]) -> Vec<MembershipEvent> {
+    if ip_packet.len() < 20 || (ip_packet[0] >> 4) != 4 {
+        return Vec::new();
+    }
+    let ihl = ((ip_packet[0] & 0x0f) as usize) * 4;
+    if ip_packet.len() < ihl || ip_packet[9] != IPPROTO_IGMP {
+        return Vec::new();
+    }
+    let igmp = &ip_packet[ihl..];
+    if igmp.len() < 8 {
+        return Vec::new();
+    }
+    match igmp[0] {
+        IGMP_V2_MEMBERSHIP_REPORT => {
+            vec![MembershipEvent::Join { group_ip: ipv4_group(&igmp[4..8]) }]
+        }
+        IGMP_V2_LEAVE_GROUP => {
+            vec![MembershipEvent::Leave { group_ip: ipv4_group(&igmp[4..8]) }]
+        }
+        IGMP_V3_MEMBERSHIP_REPORT => parse_igmp_v3_records(igmp),
+        _ => Vec::new(),
+    }
+}
+
+/// IGMPv3 membership report record types, RFC 3376 section 4.2.12.
+const IGMP_V3_CHANGE_TO_INCLUDE: u8 = 3;
+
+fn parse_igmp_v3_records(igmp: &[u8]) -> Vec<MembershipEvent> {
+    if igmp.len() < 8 {
+        return Vec::new();
+    }
+    let num_records = u16::from_be_bytes([igmp[6], igmp[7]]) as usize;
+    let mut events = Vec::with_capacity(num_records);
+    let mut offset = 8usize;
+    for _ in 0..num_records {
+        if igmp.len() < offset + 8 {
+            break;
+        }
+        let record_type = igmp[offset];
+        let aux_data_len = igmp[offset + 1] as usize;
+        let num_sources = u16::from_be_bytes([igmp[offset + 2], igmp[offset + 3]]) as usize;
+        let group_ip = ipv4_group(&igmp[offset + 4..offset + 8]);
+        // A record moving the source filter to an empty INCLUDE set is
+        // functionally a leave; anything else (including the steady-state
+        // TO_EXCLUDE/MODE_IS_EXCLUDE reports a host sends to stay joined) is
+        // treated as a join/renewal.
+        if record_type == IGMP_V3_CHANGE_TO_INCLUDE && num_sources == 0 {
+            events.push(MembershipEvent::Leave { group_ip });
+        } else {
+            events.push(MembershipEvent::Join { group_ip });
+        }
+        offset += 8 + num_sources * 4 + aux_data_len * 4;
+    }
+    events
+}
+
+fn snoop_mld(ip_packet: &[u8]) -> Vec<MembershipEvent> {
+    if ip_packet.len() < 40 || (ip_packet[0] >> 4) != 6 {
+        return Vec::new();
+    }
+    // No extension header walk: MLD is expected directly after the fixed IPv6
+    // header, which is how virtually every stack sends it.
+    if ip_packet[6] != IPPROTO_ICMPV6 {
+        return Vec::new();
+    }
+    let icmp = &ip_packet[40..];
+    if icmp.len() < 24 {
+        return Vec::new();
+    }
+    match icmp[0] {
+        MLD_LISTENER_REPORT | MLD_LISTENER_REPORT_V1 => {
+            vec![MembershipEvent::Join { group_ip: ipv6_group(&icmp[8..24]) }]
+        }
+        MLD_LISTENER_REDUCTION => {
+            vec![MembershipEvent::Leave { group_ip: ipv6_group(&icmp[8..24]) }]
+        }
+        MLDV2_LISTENER_REPORT => parse_mldv2_records(icmp),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_mldv2_records(icmp: &[u8]) -> Vec<MembershipEvent> {
+    if icmp.len() < 8 {
+        return Vec::new();
+    }
+    let num_records = u16::from_be_bytes([icmp[6], icmp[7]]) as usize;
+    let mut events = Vec::with_capacity(num_records);
+    let mut offset = 8usize;
+    for _ in 0..num_records {
+        if icmp.len() < offset + 20 {
+            break;
+        }
+        let record_type = icmp[offset];
+        let aux_data_len = icmp[offset + 1] as usize;
+        let num_sources = u16::from_be_bytes([icmp[offset + 2], icmp[offset + 3]]) as usize;
+        let group_ip = ipv6_group(&icmp[offset + 4..offset + 20]);
+        if record_type == IGMP_V3_CHANGE_TO_INCLUDE && num_sources == 0 {
+            events.push(MembershipEvent::Leave { group_ip });
+        } else {
+            events.push(MembershipEvent::Join { group_ip });
+        }
+        offset += 20 + num_sources * 16 + aux_data_len * 4;
+    }
+    events
+}
+
+fn ipv4_group(b: &[u8]) -> u128 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as u128
+}
+
+fn ipv6_group(b: &[u8]) -> u128 {
+    u128::from_be_bytes(b.try_into().unwrap())
+}
+
+/// Map a multicast IP (as produced by `snoop`) to the Ethernet multicast MAC
+/// and ADI that `MulticastGroup` uses to key a subscription.
+///
+/// IPv4 multicast MACs are `01:00:5e` + the low 23 bits of the group address,
+/// so the ADI carries the full IPv4 address to disambiguate the aliasing
+/// 9-bit collision; IPv6 multicast MACs are `33:33` + the low 32 bits of the
+/// group address, which has no such collision, so the ADI is zero.
+pub(crate) fn group_ip_to_mac_adi(group_ip: u128, is_ipv6: bool) -> (u64, u32) {
+    if is_ipv6 {
+        let low32 = (group_ip & 0xffffffff) as u32;
+        let mac = 0x3333_0000_0000u64 | low32 as u64;
+        (mac, 0)
+    } else {
+        let ipv4 = group_ip as u32;
+        let mac = 0x0100_5e00_0000u64 | (ipv4 & 0x7fffff) as u64;
+        (mac, ipv4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn igmp_v3_record(record_type: u8, group: [u8; 4]) -> Vec<u8> {
+        let mut igmp = vec![IGMP_V3_MEMBERSHIP_REPORT, 0, 0, 0, 0, 0, 0, 1];
+        igmp.push(record_type);
+        igmp.push(0); // aux data len
+        igmp.extend_from_slice(&0u16.to_be_bytes()); // num sources
+        igmp.extend_from_slice(&group);
+        igmp
+    }
+
+    #[test]
+    fn mode_is_exclude_with_no_sources_is_a_join() {
+        // The steady-state report a host retransmits periodically to stay
+        // joined in EXCLUDE mode must not be silently dropped.
+        let igmp = igmp_v3_record(2 /* MODE_IS_EXCLUDE */, [239, 1, 2, 3]);
+        let events = parse_igmp_v3_records(&igmp);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], MembershipEvent::Join { .. }));
+    }
+
+    #[test]
+    fn change_to_include_with_no_sources_is_a_leave() {
+        let igmp = igmp_v3_record(IGMP_V3_CHANGE_TO_INCLUDE, [239, 1, 2, 3]);
+        let events = parse_igmp_v3_records(&igmp);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], MembershipEvent::Leave { .. }));
+    }
+}
@@ -1,9 +1,19 @@
 // (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
 
+mod identity;
+mod igmp;
+mod multicast;
 mod multicastgroup;
+mod nameservice;
 mod networkid;
+mod session;
+mod socket;
 mod switch;
 
+pub use identity::{Address, Identity, IdentityType};
 pub use multicastgroup::MulticastGroup;
+pub use nameservice::Zone;
 pub use networkid::NetworkId;
+pub use session::SecureSession;
+pub use socket::{ip_endpoint, ReadinessEvent, SocketError, SocketHandle, SocketKind, ZtSocketStack};
 pub use switch::{Switch, SwitchInterface};
\ No newline at end of file
@@ -0,0 +1,90 @@
+// (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
+
+//! The replicate-and-gather algorithm `Switch` uses to deliver multicast and
+//! broadcast frames without flooding every member on large networks.
+//!
+//! Each node keeps a small, locally-known set of other members for every
+//! `(NetworkId, MulticastGroup)` it cares about, refreshed by periodic "LIKE"
+//! announcements from peers and by explicit gather requests/responses when
+//! local knowledge falls short of the requested fanout. A frame is replicated
+//! directly to at most `limit` of the closest/most-recently-active known
+//! members; if that isn't everyone, a `MULTICAST_GATHER` is sent upstream (to
+//! roots or the network controller) to learn more. A small per-network,
+//! fixed-size dedup ring keyed on a short frame id keeps flooding from
+//! delivering the same frame to the same node twice.
+
+use std::collections::HashMap;
+
+use crate::vl2::{MulticastGroup, NetworkId};
+
+/// How long a peer's "LIKE" announcement (or a gather result entry) is
+/// trusted before it must be renewed.
+const LIKE_TTL_SECS: u64 = 300;
+
+/// Number of recently-seen frame ids remembered per network for dedup.
+const DEDUP_RING_SIZE: usize = 256;
+
+struct Member {
+    address: u64,
+    expires_at_secs: u64,
+}
+
+/// Tracks, per network, which peers are known to want which multicast groups,
+/// and which frame ids have already been forwarded (to suppress duplicates).
+#[derive(Default)]
+pub(crate) struct MulticastPropagator {
+    known_members: HashMap<(NetworkId, MulticastGroup), Vec<Member>>,
+    dedup_rings: HashMap<NetworkId, DedupRing>,
+}
+
+impl MulticastPropagator {
+    /// Record or renew a peer's interest in a group, as announced by a "LIKE"
+    /// message or learned from a gather response.
+    pub(crate) fn add_member(&mut self, network_id: NetworkId, group: MulticastGroup, peer_address: u64, now_secs: u64) {
+        let members = self.known_members.entry((network_id, group)).or_default();
+        if let Some(m) = members.iter_mut().find(|m| m.address == peer_address) {
+            m.expires_at_secs = now_secs + LIKE_TTL_SECS;
+        } else {
+            members.push(Member { address: peer_address, expires_at_secs: now_secs + LIKE_TTL_SECS });
+        }
+    }
+
+    /// Drop expired members and return up to `limit` of the most-recently-
+    /// renewed known members, plus whether local knowledge covers `limit`
+    /// (i.e. whether a gather is unnecessary).
+    pub(crate) fn select_recipients(&mut self, network_id: NetworkId, group: MulticastGroup, now_secs: u64, limit: usize) -> (Vec<u64>, bool) {
+        let members = self.known_members.entry((network_id, group)).or_default();
+        members.retain(|m| m.expires_at_secs > now_secs);
+        members.sort_by(|a, b| b.expires_at_secs.cmp(&a.expires_at_secs));
+        let have_enough = members.len() >= limit;
+        (members.iter().take(limit).map(|m| m.address).collect(), have_enough)
+    }
+
+    /// Returns true the first time `frame_id` is seen for this network, and
+    /// records it; returns false (suppress re-forwarding) on a repeat.
+    pub(crate) fn check_and_record_frame(&mut self, network_id: NetworkId, frame_id: u64) -> bool {
+        self.dedup_rings.entry(network_id).or_insert_with(DedupRing::new).check_and_record(frame_id)
+    }
+}
+
+/// A small fixed-size ring of recently-forwarded frame ids, used instead of an
+/// ever-growing set so memory use per network is bounded.
+struct DedupRing {
+    seen: [u64; DEDUP_RING_SIZE],
+    next: usize,
+}
+
+impl DedupRing {
+    fn new() -> Self {
+        Self { seen: [u64::MAX; DEDUP_RING_SIZE], next: 0 }
+    }
+
+    fn check_and_record(&mut self, frame_id: u64) -> bool {
+        if self.seen.contains(&frame_id) {
+            return false;
+        }
+        self.seen[self.next] = frame_id;
+        self.next = (self.next + 1) % DEDUP_RING_SIZE;
+        true
+    }
+}
@@ -0,0 +1,33 @@
+// (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
+
+use std::fmt::{Display, Formatter};
+
+/// An Ethernet multicast group: a 48-bit multicast MAC plus an additional
+/// distinguishing identifier (ADI).
+///
+/// The ADI lets IPv4 multicast groups (which all reduce to the same low 23
+/// bits of a 01:00:5e:xx:xx:xx MAC) be told apart by mixing in the group's
+/// full IPv4 address, so two different multicast IPs that alias to the same
+/// MAC are not treated as the same subscription.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct MulticastGroup {
+    /// 48-bit multicast MAC address, stored in the low bits of a u64.
+    pub mac: u64,
+    pub adi: u32,
+}
+
+impl MulticastGroup {
+    pub fn new(mac: u64, adi: u32) -> Self {
+        Self { mac: mac & 0xffffffffffff, adi }
+    }
+}
+
+impl Display for MulticastGroup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.adi == 0 {
+            write!(f, "{:012x}", self.mac)
+        } else {
+            write!(f, "{:012x}/{:08x}", self.mac, self.adi)
+        }
+    }
+}
@@ -0,0 +1,436 @@
+// (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
+
+//! In-network name resolution, keyed on [`NetworkId`].
+//!
+//! The controller distributes a signed zone (name -> member addresses/IPs)
+//! for each network it manages; `Switch` caches the zone and answers A/AAAA/
+//! PTR-shaped DNS queries it snoops on the VL2 interface for a configurable
+//! search domain, synthesizing responses locally instead of forwarding the
+//! query out to a real resolver. Runtime overrides let the host application
+//! add or replace individual names on top of whatever the controller pushed,
+//! and negative answers are cached for a TTL so repeated misses don't have to
+//! walk the zone every time.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::vl2::NetworkId;
+
+/// How long a negative (NXDOMAIN) answer is cached before the name is looked
+/// up again.
+const NEGATIVE_CACHE_TTL_SECS: u64 = 30;
+
+/// A signed set of name -> address mappings for one network, as distributed
+/// by that network's controller.
+///
+/// Signature verification is assumed to have already happened (via the same
+/// identity machinery `Switch` uses for peer authentication) before a zone is
+/// handed to [`NameService::push_zone`]; this type only holds the verified
+/// contents.
+#[derive(Clone, Default)]
+pub struct Zone {
+    /// The DNS search domain this zone answers for, e.g. "member.example".
+    pub search_domain: String,
+    pub records: HashMap<String, Vec<IpAddr>>,
+}
+
+#[derive(Default)]
+pub(crate) struct NameService {
+    zones: HashMap<NetworkId, Zone>,
+    /// Runtime overrides, applied on top of (and before falling back to) the
+    /// controller-pushed zone for a network.
+    overrides: HashMap<(NetworkId, String), Vec<IpAddr>>,
+    negative_cache: HashMap<(NetworkId, String), u64>,
+    /// Reverse (address -> name) index, rebuilt from a zone's records plus
+    /// whatever overrides are in effect whenever either changes, so PTR
+    /// queries can be answered in O(1) instead of scanning every record.
+    reverse: HashMap<(NetworkId, IpAddr), String>,
+}
+
+impl NameService {
+    pub(crate) fn push_zone(&mut self, network_id: NetworkId, zone: Zone) {
+        self.negative_cache.retain(|(n, _), _| *n != network_id);
+        self.zones.insert(network_id, zone);
+        self.rebuild_reverse(network_id);
+    }
+
+    pub(crate) fn set_override(&mut self, network_id: NetworkId, name: String, addresses: Vec<IpAddr>) {
+        self.negative_cache.remove(&(network_id, name.clone()));
+        self.overrides.insert((network_id, name), addresses);
+        self.rebuild_reverse(network_id);
+    }
+
+    pub(crate) fn clear_override(&mut self, network_id: NetworkId, name: &str) {
+        self.overrides.remove(&(network_id, name.to_string()));
+        self.rebuild_reverse(network_id);
+    }
+
+    /// Recompute the reverse index for `network_id` from its zone and
+    /// overrides. Overrides win ties, matching `resolve`'s own precedence.
+    fn rebuild_reverse(&mut self, network_id: NetworkId) {
+        self.reverse.retain(|(n, _), _| *n != network_id);
+        if let Some(zone) = self.zones.get(&network_id) {
+            for (name, addrs) in &zone.records {
+                for addr in addrs {
+                    self.reverse.entry((network_id, *addr)).or_insert_with(|| name.clone());
+                }
+            }
+        }
+        for ((n, name), addrs) in &self.overrides {
+            if *n != network_id {
+                continue;
+            }
+            for addr in addrs {
+                self.reverse.insert((network_id, *addr), name.clone());
+            }
+        }
+    }
+
+    /// Look up the name that PTR queries for `addr` should resolve to, if any.
+    pub(crate) fn resolve_ptr(&self, network_id: NetworkId, addr: IpAddr) -> Option<&str> {
+        self.reverse.get(&(network_id, addr)).map(|s| s.as_str())
+    }
+
+    /// Resolve `name` (already lower-cased, without a trailing dot) within
+    /// `network_id`, checking the negative cache, then overrides, then the
+    /// zone. Returns `None` both for a name with an unexpired negative cache
+    /// entry and for a name found in neither overrides nor zone, which then
+    /// gets a negative cache entry for `NEGATIVE_CACHE_TTL_SECS`.
+    pub(crate) fn resolve(&mut self, network_id: NetworkId, now_secs: u64, name: &str) -> Option<Vec<IpAddr>> {
+        let key = (network_id, name.to_string());
+        if let Some(expiry) = self.negative_cache.get(&key) {
+            if *expiry > now_secs {
+                return None;
+            }
+        }
+        if let Some(addrs) = self.overrides.get(&key) {
+            return Some(addrs.clone());
+        }
+        if let Some(zone) = self.zones.get(&network_id) {
+            if let Some(addrs) = zone.records.get(name) {
+                return Some(addrs.clone());
+            }
+        }
+        self.negative_cache.insert(key, now_secs + NEGATIVE_CACHE_TTL_SECS);
+        None
+    }
+
+    pub(crate) fn search_domain(&self, network_id: NetworkId) -> Option<&str> {
+        self.zones.get(&network_id).map(|z| z.search_domain.as_str())
+    }
+}
+
+/// DNS record types this service answers, RFC 1035/3596.
+pub(crate) const DNS_TYPE_A: u16 = 1;
+pub(crate) const DNS_TYPE_PTR: u16 = 12;
+pub(crate) const DNS_TYPE_AAAA: u16 = 28;
+
+/// A minimally parsed DNS query: the header id (echoed back in the reply),
+/// the question name, and the question type.
+pub(crate) struct DnsQuery {
+    pub(crate) id: u16,
+    pub(crate) name: String,
+    pub(crate) qtype: u16,
+}
+
+/// Parse the question section of a DNS message. Only single-question queries
+/// (the overwhelming majority sent by stub resolvers) are recognized.
+pub(crate) fn parse_query(packet: &[u8]) -> Option<DnsQuery> {
+    if packet.len() < 12 {
+        return None;
+    }
+    let id = u16::from_be_bytes([packet[0], packet[1]]);
+    let qdcount = u16::from_be_bytes([packet[4], packet[5]]);
+    if qdcount != 1 {
+        return None;
+    }
+    let mut offset = 12;
+    let mut labels = Vec::new();
+    loop {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            offset += 1;
+            break;
+        }
+        offset += 1;
+        labels.push(std::str::from_utf8(packet.get(offset..offset + len)?).ok()?.to_string());
+        offset += len;
+    }
+    let qtype = u16::from_be_bytes([*packet.get(offset)?, *packet.get(offset + 1)?]);
+    Some(DnsQuery { id, name: labels.join("."), qtype })
+}
+
+/// What a resolved query answers with, distinguishing the record shapes this
+/// service knows how to serialize so `build_response` never has to guess
+/// which RDATA format applies to which answer.
+pub(crate) enum DnsAnswer {
+    /// A/AAAA answers: one record per address, matched against the query's
+    /// address family.
+    Addresses(Vec<IpAddr>),
+    /// A PTR answer: the single name `addr` resolves to.
+    Name(String),
+}
+
+/// Build a DNS response for `query`, or an empty-answer (NXDOMAIN-equivalent)
+/// response if `answer` is `None`.
+pub(crate) fn build_response(query: &DnsQuery, answer: Option<&DnsAnswer>, ttl_secs: u32) -> Vec<u8> {
+    // Filter to the addresses that actually match the question's family once,
+    // up front, so ANCOUNT (below) and the records actually serialized (in
+    // the match below) can never drift apart the way they would if ANCOUNT
+    // were taken from the unfiltered list.
+    let matching_addrs = match answer {
+        Some(DnsAnswer::Addresses(addrs)) => {
+            addrs.iter().filter(|addr| matches!((addr, query.qtype), (IpAddr::V4(_), DNS_TYPE_A) | (IpAddr::V6(_), DNS_TYPE_AAAA))).collect::<Vec<_>>()
+        }
+        _ => Vec::new(),
+    };
+    let ancount = match answer {
+        Some(DnsAnswer::Addresses(_)) => matching_addrs.len(),
+        Some(DnsAnswer::Name(_)) => 1,
+        None => 0,
+    };
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&query.id.to_be_bytes());
+    // QR=1 (response), AA=1 (authoritative), RCODE = NXDOMAIN(3) if empty else 0.
+    let rcode: u8 = if ancount == 0 { 3 } else { 0 };
+    out.extend_from_slice(&[0x84, rcode]);
+    out.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    out.extend_from_slice(&(ancount as u16).to_be_bytes()); // ANCOUNT
+    out.extend_from_slice(&0u16.to_be_bytes()); // NSCOUNT
+    out.extend_from_slice(&0u16.to_be_bytes()); // ARCOUNT
+
+    write_name(&mut out, &query.name);
+    out.extend_from_slice(&query.qtype.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes()); // QCLASS IN
+
+    match answer {
+        Some(DnsAnswer::Addresses(_)) => {
+            for addr in matching_addrs {
+                write_name(&mut out, &query.name);
+                match addr {
+                    IpAddr::V4(v4) => {
+                        out.extend_from_slice(&DNS_TYPE_A.to_be_bytes());
+                        out.extend_from_slice(&1u16.to_be_bytes());
+                        out.extend_from_slice(&ttl_secs.to_be_bytes());
+                        out.extend_from_slice(&4u16.to_be_bytes());
+                        out.extend_from_slice(&v4.octets());
+                    }
+                    IpAddr::V6(v6) => {
+                        out.extend_from_slice(&DNS_TYPE_AAAA.to_be_bytes());
+                        out.extend_from_slice(&1u16.to_be_bytes());
+                        out.extend_from_slice(&ttl_secs.to_be_bytes());
+                        out.extend_from_slice(&16u16.to_be_bytes());
+                        out.extend_from_slice(&v6.octets());
+                    }
+                }
+            }
+        }
+        Some(DnsAnswer::Name(name)) => {
+            write_name(&mut out, &query.name);
+            out.extend_from_slice(&DNS_TYPE_PTR.to_be_bytes());
+            out.extend_from_slice(&1u16.to_be_bytes());
+            out.extend_from_slice(&ttl_secs.to_be_bytes());
+            let mut rdata = Vec::new();
+            write_name(&mut rdata, name);
+            out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            out.extend_from_slice(&rdata);
+        }
+        None => {}
+    }
+    out
+}
+
+/// Parse a reverse-lookup query name (`"1.2.3.4.in-addr.arpa"` or the nibble
+/// form under `"ip6.arpa"`) back into the address it names.
+pub(crate) fn parse_ptr_name(name: &str) -> Option<IpAddr> {
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets: Vec<u8> = rest.split('.').map(|label| label.parse().ok()).collect::<Option<_>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        Some(IpAddr::from([octets[0], octets[1], octets[2], octets[3]]))
+    } else if let Some(rest) = name.strip_suffix(".ip6.arpa") {
+        let nibbles: Vec<u8> = rest.split('.').map(|label| u8::from_str_radix(label, 16).ok()).collect::<Option<_>>()?;
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let mut segments = [0u8; 16];
+        for (i, nibble) in nibbles.iter().rev().enumerate() {
+            segments[i / 2] |= nibble << (if i % 2 == 0 { 4 } else { 0 });
+        }
+        Some(IpAddr::from(segments))
+    } else {
+        None
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// Either half of a query this service knows how to answer, passed to the
+/// single `resolve` callback in [`snoop_and_answer`] so it only ever needs one
+/// (possibly mutable) borrow of the caller's name table, regardless of which
+/// kind of query actually came in.
+pub(crate) enum Lookup<'a> {
+    /// An A/AAAA query for `name`, already stripped of the search domain.
+    Forward(&'a str),
+    /// A PTR query for the name `addr` resolves to.
+    Ptr(IpAddr),
+}
+
+/// If `payload` (the Ethertype-0800 IPv4 packet following an Ethernet header)
+/// is a UDP/53 DNS query this service can answer (an A/AAAA query for a name
+/// under `search_domain`, or a PTR query for a reverse-lookup name), resolve
+/// it with `resolve` and return the full IPv4+UDP+DNS reply packet (with
+/// source and destination swapped, ready to hand back to whoever asked).
+/// Returns `None` for anything else, leaving the frame to be delivered/
+/// forwarded normally.
+pub(crate) fn snoop_and_answer(payload: &[u8], search_domain: &str, ttl_secs: u32, mut resolve: impl FnMut(Lookup) -> Option<DnsAnswer>) -> Option<Vec<u8>> {
+    if payload.len() < 28 || (payload[0] >> 4) != 4 {
+        return None;
+    }
+    let ihl = ((payload[0] & 0x0f) as usize) * 4;
+    if payload.len() < ihl + 8 || payload[9] != 17 {
+        return None;
+    }
+    let udp = &payload[ihl..];
+    let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+    if dst_port != 53 {
+        return None;
+    }
+    let dns = &udp[8..];
+    let query = parse_query(dns)?;
+    let fqdn = query.name.to_ascii_lowercase();
+
+    let answer = if query.qtype == DNS_TYPE_PTR {
+        let addr = parse_ptr_name(&fqdn)?;
+        resolve(Lookup::Ptr(addr))
+    } else if query.qtype == DNS_TYPE_A || query.qtype == DNS_TYPE_AAAA {
+        let bare = if fqdn == search_domain { "" } else { fqdn.strip_suffix(&format!(".{search_domain}"))? };
+        resolve(Lookup::Forward(bare))
+    } else {
+        return None;
+    };
+    let answer = build_response(&query, answer.as_ref(), ttl_secs);
+
+    let src_ip = &payload[12..16];
+    let dst_ip = &payload[16..20];
+    let src_port = &udp[0..2];
+
+    let mut reply_udp = Vec::with_capacity(8 + answer.len());
+    reply_udp.extend_from_slice(&53u16.to_be_bytes());
+    reply_udp.extend_from_slice(src_port);
+    reply_udp.extend_from_slice(&((8 + answer.len()) as u16).to_be_bytes());
+    reply_udp.extend_from_slice(&0u16.to_be_bytes()); // checksum, optional for IPv4
+    reply_udp.extend_from_slice(&answer);
+
+    let mut reply_ip = Vec::with_capacity(20 + reply_udp.len());
+    reply_ip.push(0x45);
+    reply_ip.push(0);
+    reply_ip.extend_from_slice(&((20 + reply_udp.len()) as u16).to_be_bytes());
+    reply_ip.extend_from_slice(&0u16.to_be_bytes()); // identification
+    reply_ip.extend_from_slice(&0u16.to_be_bytes()); // flags/fragment offset
+    reply_ip.push(64); // TTL
+    reply_ip.push(17); // UDP
+    reply_ip.extend_from_slice(&0u16.to_be_bytes()); // checksum filled below
+    reply_ip.extend_from_slice(dst_ip); // swapped: we are now the source
+    reply_ip.extend_from_slice(src_ip);
+    reply_ip.extend_from_slice(&reply_udp);
+
+    let checksum = ipv4_header_checksum(&reply_ip[0..20]);
+    reply_ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    Some(reply_ip)
+}
+
+fn ipv4_header_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in header.chunks(2) {
+        let word = if chunk.len() == 2 { u16::from_be_bytes([chunk[0], chunk[1]]) } else { u16::from_be_bytes([chunk[0], 0]) };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn query(name: &str, qtype: u16) -> DnsQuery {
+        DnsQuery { id: 0x1234, name: name.to_string(), qtype }
+    }
+
+    #[test]
+    fn ancount_matches_serialized_answer_count_for_ptr() {
+        let q = query("4.3.2.1.in-addr.arpa", DNS_TYPE_PTR);
+        let answer = DnsAnswer::Name("host.member.example".to_string());
+        let resp = build_response(&q, Some(&answer), 60);
+        let ancount = u16::from_be_bytes([resp[6], resp[7]]);
+        assert_eq!(ancount, 1);
+        // The reply must actually carry exactly `ancount` answer records, not
+        // just claim to.
+        let mut name_bytes = Vec::new();
+        write_name(&mut name_bytes, &q.name);
+        let mut rdata = Vec::new();
+        write_name(&mut rdata, "host.member.example");
+        let question_len = name_bytes.len() + 2 + 2; // QTYPE, QCLASS
+        let one_ptr_record_len = name_bytes.len() + 2 + 2 + 4 + 2 + rdata.len(); // TYPE, CLASS, TTL, RDLENGTH, RDATA
+        assert_eq!(resp.len(), 12 + question_len + one_ptr_record_len);
+    }
+
+    #[test]
+    fn ancount_is_zero_when_nothing_resolves() {
+        let q = query("4.3.2.1.in-addr.arpa", DNS_TYPE_PTR);
+        let resp = build_response(&q, None, 60);
+        let ancount = u16::from_be_bytes([resp[6], resp[7]]);
+        assert_eq!(ancount, 0);
+        assert_eq!(resp[3], 3); // RCODE NXDOMAIN
+    }
+
+    #[test]
+    fn ancount_matches_serialized_answer_count_for_dual_stack_host() {
+        // A dual-stack host has both an A and an AAAA record; a type-A query
+        // must report ANCOUNT=1 and serialize exactly that one record, not
+        // ANCOUNT=2 with only the A record actually written.
+        let q = query("host.member.example", DNS_TYPE_A);
+        let answer = DnsAnswer::Addresses(vec![IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), IpAddr::V6(std::net::Ipv6Addr::LOCALHOST)]);
+        let resp = build_response(&q, Some(&answer), 60);
+        let ancount = u16::from_be_bytes([resp[6], resp[7]]);
+        assert_eq!(ancount, 1);
+
+        // The reply must carry exactly one RR, not claim one while (as the
+        // bug did) actually writing a second, AAAA-shaped record behind it.
+        let mut name_bytes = Vec::new();
+        write_name(&mut name_bytes, &q.name);
+        let question_len = name_bytes.len() + 2 + 2; // QTYPE, QCLASS
+        let one_a_record_len = name_bytes.len() + 2 + 2 + 4 + 2 + 4; // TYPE, CLASS, TTL, RDLENGTH, RDATA
+        assert_eq!(resp.len(), 12 + question_len + one_a_record_len);
+    }
+
+    #[test]
+    fn parse_ptr_name_roundtrips_ipv4() {
+        let addr = parse_ptr_name("4.3.2.1.in-addr.arpa").unwrap();
+        assert_eq!(addr, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn reverse_index_resolves_after_push_zone() {
+        let mut ns = NameService::default();
+        let network_id = NetworkId(1);
+        let mut records = HashMap::new();
+        records.insert("host".to_string(), vec![IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))]);
+        ns.push_zone(network_id, Zone { search_domain: "member.example".to_string(), records });
+        assert_eq!(ns.resolve_ptr(network_id, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))), Some("host"));
+    }
+}
@@ -0,0 +1,35 @@
+// (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
+
+use std::fmt::{Display, Formatter};
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+/// A 64-bit virtual network identifier.
+///
+/// By convention the most significant 40 bits are the ZeroTier address of the
+/// network's controller and the least significant 24 bits are a network number
+/// chosen by that controller, but nothing in this type enforces that structure.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub struct NetworkId(pub u64);
+
+impl NetworkId {
+    /// The 40-bit address of the node that is presumed to be this network's controller.
+    #[inline(always)]
+    pub fn controller_address(&self) -> u64 {
+        self.0 >> 24
+    }
+}
+
+impl Display for NetworkId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for NetworkId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(NetworkId)
+    }
+}
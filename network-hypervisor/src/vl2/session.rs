@@ -0,0 +1,809 @@
+// (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
+
+//! A ZSSP-style (Noise-IK flavored) forward-secret transport session.
+//!
+//! The initiator already knows the responder's static public key (the way it
+//! knows any peer's key: out of band, via the address/identity directory),
+//! so only the initiator's identity needs hiding. The handshake is two
+//! messages:
+//!
+//!   1. initiator -> responder: an ephemeral public key, a commitment to the
+//!      responder's static key (so a misdirected message is caught early),
+//!      and the initiator's own static key encrypted under a key derived from
+//!      `es = DH(e_i, S_r)`.
+//!   2. responder -> initiator: its own ephemeral public key and its static
+//!      key encrypted under a key derived from the chaining key after `ee` is
+//!      mixed in.
+//!
+//! Every DH output (`es`, `ee`, `se`) is folded into a running chaining key
+//! with HKDF as the handshake proceeds, so the final traffic keys depend on
+//! all three and a compromise of any single static key does not by itself
+//! expose past or future traffic. `se` binds in the initiator's static key
+//! exactly like `es` binds in the responder's, so both peers are mutually
+//! authenticated once the handshake completes.
+//!
+//! Once established, a session also rekeys itself: [`SecureSession::needs_rekey`]
+//! trips after a time or byte budget, at which point either side may run a
+//! second ephemeral exchange (initiator side via [`SecureSession::begin_rekey`],
+//! responder side automatically, by feeding a fresh message 1 to
+//! [`SecureSession::handle_handshake`] on an already-established session) without
+//! disturbing the traffic keys already in use. The old receive key is kept
+//! around for a grace period after the new one installs, so packets the peer
+//! encrypted under it just before rolling over still decrypt.
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use p384::{PublicKey as P384PublicKey, SecretKey as P384SecretKey};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::vl2::identity::{self, IdentityType};
+
+/// Bit flags for the capabilities byte each side includes (inside its
+/// encrypted identity blob) to declare which suites it supports, so its peer
+/// can negotiate the strongest mutually supported one via
+/// [`identity::negotiate`] without a separate round trip.
+const CAP_LEGACY: u8 = 0x01;
+const CAP_P384: u8 = 0x02;
+
+/// Size, in bytes, of a P-384 public key in SEC1 compressed form.
+const P384_PUBLIC_KEY_LEN: usize = 49;
+
+/// Rekey after this many seconds even if the byte counter hasn't tripped.
+const REKEY_AFTER_TIME_SECS: u64 = 600;
+/// Rekey after this many bytes even if the timer hasn't tripped.
+const REKEY_AFTER_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Size in bits of the replay window bitmap. ZSSP-style sessions use a wide
+/// window since packets may legitimately arrive out of order across paths.
+const REPLAY_WINDOW_BITS: u64 = 1024;
+const REPLAY_WINDOW_WORDS: usize = (REPLAY_WINDOW_BITS / 64) as usize;
+
+/// A sliding-window replay filter keyed on a monotonically increasing counter.
+///
+/// `window` is a little-endian multi-word bitmap: word 0 holds offsets 0..63
+/// (offset 0 being the most recently accepted counter), word 1 holds offsets
+/// 64..127, and so on. When a new highest counter arrives, every existing
+/// offset grows by the gap, which is a bit-level left shift of that bitmap
+/// treated as one big number (word 0 least significant) -- not merely a
+/// whole-word rotation, since most traffic advances the counter by far less
+/// than 64 at a time and a word-only shift would leave the window unchanged.
+struct ReplayFilter {
+    highest: u64,
+    window: [u64; REPLAY_WINDOW_WORDS],
+}
+
+impl ReplayFilter {
+    fn new() -> Self {
+        Self { highest: 0, window: [0u64; REPLAY_WINDOW_WORDS] }
+    }
+
+    /// Returns true if `counter` has not been seen before and records it.
+    fn check_and_record(&mut self, counter: u64) -> bool {
+        if counter > self.highest {
+            let advance = counter - self.highest;
+            if advance >= REPLAY_WINDOW_BITS {
+                self.window = [0u64; REPLAY_WINDOW_WORDS];
+            } else {
+                shift_left_bits(&mut self.window, advance);
+            }
+            self.highest = counter;
+            self.set_bit(0);
+            true
+        } else {
+            let back = self.highest - counter;
+            if back >= REPLAY_WINDOW_BITS {
+                return false;
+            }
+            if self.test_bit(back) {
+                false
+            } else {
+                self.set_bit(back);
+                true
+            }
+        }
+    }
+
+    fn set_bit(&mut self, offset: u64) {
+        self.window[(offset / 64) as usize] |= 1u64 << (offset % 64);
+    }
+
+    fn test_bit(&self, offset: u64) -> bool {
+        (self.window[(offset / 64) as usize] >> (offset % 64)) & 1 != 0
+    }
+}
+
+/// Shift every set bit in `window` up by `bits` positions, discarding bits
+/// that fall off the top and filling vacated low bits with zero. Standard
+/// multi-word big-integer left shift, with `window[0]` as the least
+/// significant word.
+fn shift_left_bits(window: &mut [u64; REPLAY_WINDOW_WORDS], bits: u64) {
+    let word_shift = (bits / 64) as usize;
+    let bit_shift = (bits % 64) as u32;
+    for i in (0..window.len()).rev() {
+        let lower = if i >= word_shift { window[i - word_shift] } else { 0 };
+        window[i] = if bit_shift == 0 {
+            lower
+        } else {
+            let upper = if i >= word_shift + 1 { window[i - word_shift - 1] } else { 0 };
+            (lower << bit_shift) | (upper >> (64 - bit_shift))
+        };
+    }
+}
+
+enum HandshakeState {
+    /// Responder: no message received yet.
+    AwaitingFirstMessage,
+    /// Initiator: message 1 sent, waiting for the responder's reply.
+    AwaitingResponse { ephemeral_secret: EphemeralSecret, ck_after_es: [u8; 32] },
+    Established,
+}
+
+struct DirectionalKeys {
+    key: [u8; 32],
+    counter: u64,
+    established_at_secs: u64,
+    bytes_sent: u64,
+}
+
+/// One forward-secret session between this node and a single peer.
+pub struct SecureSession {
+    /// This node's own static secret(s), kept for the life of the session
+    /// (rather than just until the first handshake completes) so a later
+    /// rekey can run a fresh ephemeral exchange without needing them handed
+    /// back in.
+    our_static: StaticSecret,
+    our_p384_static: Option<P384SecretKey>,
+    /// The peer's x25519 static public key: supplied up front for a session
+    /// this node initiated, or learned from the first handshake message for
+    /// one it responded to. `None` only until a responder session's first
+    /// message has been processed.
+    peer_static: Option<PublicKey>,
+    state: HandshakeState,
+    /// A rekey this node started itself, awaiting the peer's reply. `state`
+    /// and the active traffic keys are left untouched while this is set, so
+    /// outbound traffic keeps flowing on the old keys until the new ones are
+    /// ready to install. Unset for a rekey the peer initiates, since the
+    /// responder side of a handshake completes in one step and has no
+    /// intermediate state to hold.
+    rekey: Option<HandshakeState>,
+    send_keys: Option<DirectionalKeys>,
+    recv_keys: Option<DirectionalKeys>,
+    /// The keys a rekey just superseded, kept around so packets the peer
+    /// encrypted under them just before rollover still decrypt instead of
+    /// being dropped. Overwritten (not merged) by the next rekey.
+    prev_recv_keys: Option<DirectionalKeys>,
+    replay: ReplayFilter,
+    prev_replay: Option<ReplayFilter>,
+    /// The identity suite this session negotiated with its peer. Kept on the
+    /// session (rather than looked up separately) so callers driving the
+    /// handshake can see what was actually agreed without re-deriving it.
+    identity_type: IdentityType,
+}
+
+impl SecureSession {
+    /// Start a handshake as the initiator, addressing `their_static` (already
+    /// known, e.g. from the peer's `Identity`). `our_p384_static`, if this
+    /// node has a P-384 identity too, is offered to the peer and combined
+    /// into the traffic keys if the peer turns out to support P-384 as well.
+    /// Returns the session (not yet established) and the first handshake
+    /// message to `wire_send`. The suite actually used is negotiated from
+    /// what both sides declare and isn't known until the handshake completes,
+    /// so [`SecureSession::identity_type`] reads as [`IdentityType::Legacy`]
+    /// until then.
+    pub fn new_initiator(our_static: &StaticSecret, our_p384_static: Option<&P384SecretKey>, their_static: &PublicKey) -> (Self, Vec<u8>) {
+        let (ephemeral_secret, ck_after_es, msg) = build_message1(our_static, our_p384_static, their_static);
+        let session = Self {
+            our_static: our_static.clone(),
+            our_p384_static: our_p384_static.cloned(),
+            peer_static: Some(*their_static),
+            state: HandshakeState::AwaitingResponse { ephemeral_secret, ck_after_es },
+            rekey: None,
+            send_keys: None,
+            recv_keys: None,
+            prev_recv_keys: None,
+            replay: ReplayFilter::new(),
+            prev_replay: None,
+            identity_type: IdentityType::Legacy,
+        };
+        (session, msg)
+    }
+
+    /// Start a handshake as the responder. `our_static` (and, if this node
+    /// has one, `our_p384_static`) are this node's own static secrets; the
+    /// initiator's identity (and the suite it supports) is only known once
+    /// the first handshake message arrives.
+    pub fn new_responder(our_static: StaticSecret, our_p384_static: Option<P384SecretKey>) -> Self {
+        Self {
+            our_static,
+            our_p384_static,
+            peer_static: None,
+            state: HandshakeState::AwaitingFirstMessage,
+            rekey: None,
+            send_keys: None,
+            recv_keys: None,
+            prev_recv_keys: None,
+            replay: ReplayFilter::new(),
+            prev_replay: None,
+            // Placeholder until the first handshake message is parsed.
+            identity_type: IdentityType::Legacy,
+        }
+    }
+
+    /// The identity suite in use for this session, as negotiated during the
+    /// handshake (or the default until a handshake message has been seen).
+    pub fn identity_type(&self) -> IdentityType {
+        self.identity_type
+    }
+
+    /// Start a fresh ephemeral exchange with the already-established peer,
+    /// returning the first handshake message to `wire_send`. The session
+    /// keeps using its current traffic keys until the peer replies and the
+    /// new ones install, so this doesn't interrupt in-flight traffic.
+    /// Returns `None` if the session isn't established yet, a rekey is
+    /// already in progress, or (unreachably, since every established
+    /// session has learned its peer's static key by then) the peer's static
+    /// key isn't known.
+    pub fn begin_rekey(&mut self) -> Option<Vec<u8>> {
+        if !self.is_established() || self.rekey.is_some() {
+            return None;
+        }
+        let their_static = self.peer_static?;
+        let (ephemeral_secret, ck_after_es, msg) = build_message1(&self.our_static, self.our_p384_static.as_ref(), &their_static);
+        self.rekey = Some(HandshakeState::AwaitingResponse { ephemeral_secret, ck_after_es });
+        Some(msg)
+    }
+
+    /// Feed an incoming handshake message to this session, returning the next
+    /// message to send back (if any). `now_secs` is stamped as the new keys'
+    /// `established_at_secs` if the handshake (or rekey) completes.
+    pub fn handle_handshake(&mut self, data: &[u8], now_secs: u64) -> Option<Vec<u8>> {
+        if let Some(rekey_state) = self.rekey.take() {
+            // We started this rekey ourselves; `data` must be the peer's
+            // reply to it.
+            return match rekey_state {
+                HandshakeState::AwaitingResponse { ephemeral_secret, ck_after_es } => self.handle_response(data, now_secs, ephemeral_secret, ck_after_es, true),
+                unreachable_state => {
+                    self.rekey = Some(unreachable_state);
+                    None
+                }
+            };
+        }
+        match std::mem::replace(&mut self.state, HandshakeState::Established) {
+            HandshakeState::AwaitingFirstMessage => self.handle_first_message(data, now_secs, false),
+            HandshakeState::AwaitingResponse { ephemeral_secret, ck_after_es } => self.handle_response(data, now_secs, ephemeral_secret, ck_after_es, false),
+            HandshakeState::Established => {
+                // Already established on our side, and not a reply to a
+                // rekey we started: the peer is initiating one. Process its
+                // message 1 without disturbing `state` or the active keys.
+                self.state = HandshakeState::Established;
+                self.handle_first_message(data, now_secs, true)
+            }
+        }
+    }
+
+    fn handle_first_message(&mut self, data: &[u8], now_secs: u64, is_rekey: bool) -> Option<Vec<u8>> {
+        if data.len() < 32 + 32 + 32 + 16 {
+            if !is_rekey {
+                self.state = HandshakeState::AwaitingFirstMessage;
+            }
+            return None;
+        }
+        let e_i_pub = PublicKey::from(<[u8; 32]>::try_from(&data[0..32]).ok()?);
+        let commitment = &data[32..64];
+        let encrypted_identity = &data[64..];
+
+        let our_static_public = PublicKey::from(&self.our_static);
+        if commitment != hash_commitment(our_static_public.as_bytes()) {
+            if !is_rekey {
+                self.state = HandshakeState::AwaitingFirstMessage;
+            }
+            return None;
+        }
+
+        let es = self.our_static.diffie_hellman(&e_i_pub);
+        let (ck_after_es, k1) = mix_key(&[0u8; 32], es.as_bytes());
+        let Some(initiator_blob) = aead_decrypt(&k1, encrypted_identity) else {
+            if !is_rekey {
+                self.state = HandshakeState::AwaitingFirstMessage;
+            }
+            return None;
+        };
+        let Some((initiator_static_pub, initiator_p384_pub)) = decode_identity_blob(&initiator_blob) else {
+            if !is_rekey {
+                self.state = HandshakeState::AwaitingFirstMessage;
+            }
+            return None;
+        };
+
+        let ephemeral_secret = EphemeralSecret::random_from_rng(rand_core::OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral_secret);
+
+        let ee = ephemeral_secret.diffie_hellman(&e_i_pub);
+        let (ck_after_ee, k2) = mix_key(&ck_after_es, ee.as_bytes());
+        let se = ephemeral_secret.diffie_hellman(&initiator_static_pub);
+        let (ck_final, _) = mix_key(&ck_after_ee, se.as_bytes());
+
+        self.peer_static = Some(initiator_static_pub);
+        let our_p384_static = self.our_p384_static.clone();
+        self.install_keys(now_secs, &ck_final, Role::Responder, our_p384_static.as_ref(), initiator_p384_pub.as_ref(), is_rekey);
+
+        let identity_blob = encode_identity_blob(&our_static_public, our_p384_static.as_ref());
+        let encrypted_reply_identity = aead_encrypt(&k2, &identity_blob);
+        let mut msg = Vec::with_capacity(32 + encrypted_reply_identity.len());
+        msg.extend_from_slice(ephemeral_public.as_bytes());
+        msg.extend_from_slice(&encrypted_reply_identity);
+        Some(msg)
+    }
+
+    fn handle_response(&mut self, data: &[u8], now_secs: u64, ephemeral_secret: EphemeralSecret, ck_after_es: [u8; 32], is_rekey: bool) -> Option<Vec<u8>> {
+        if data.len() < 32 + 16 {
+            self.restore_awaiting_response(ephemeral_secret, ck_after_es, is_rekey);
+            return None;
+        }
+        let e_r_pub = match <[u8; 32]>::try_from(&data[0..32]) {
+            Ok(b) => PublicKey::from(b),
+            Err(_) => return None,
+        };
+        let encrypted_identity = &data[32..];
+
+        let ee = ephemeral_secret.diffie_hellman(&e_r_pub);
+        let (ck_after_ee, k2) = mix_key(&ck_after_es, ee.as_bytes());
+        let Some(responder_blob) = aead_decrypt(&k2, encrypted_identity) else {
+            self.restore_awaiting_response(ephemeral_secret, ck_after_es, is_rekey);
+            return None;
+        };
+        let Some((responder_static_pub, responder_p384_pub)) = decode_identity_blob(&responder_blob) else {
+            self.restore_awaiting_response(ephemeral_secret, ck_after_es, is_rekey);
+            return None;
+        };
+
+        let se = self.our_static.diffie_hellman(&e_r_pub);
+        let (ck_final, _) = mix_key(&ck_after_ee, se.as_bytes());
+
+        self.peer_static = Some(responder_static_pub);
+        let our_p384_static = self.our_p384_static.clone();
+        self.install_keys(now_secs, &ck_final, Role::Initiator, our_p384_static.as_ref(), responder_p384_pub.as_ref(), is_rekey);
+        None
+    }
+
+    /// Put a failed `handle_response` attempt's handshake state back where it
+    /// came from -- `rekey` if this was a self-initiated rekey, `state`
+    /// otherwise -- so a corrupted or spoofed message 2 doesn't leave the
+    /// real handshake state unrecoverable.
+    fn restore_awaiting_response(&mut self, ephemeral_secret: EphemeralSecret, ck_after_es: [u8; 32], is_rekey: bool) {
+        let state = HandshakeState::AwaitingResponse { ephemeral_secret, ck_after_es };
+        if is_rekey {
+            self.rekey = Some(state);
+        } else {
+            self.state = state;
+        }
+    }
+
+    /// Negotiate the suite both sides actually support, combine the x25519
+    /// chaining key with a P-384 static-static agreement if the negotiated
+    /// suite is P-384, and install the resulting traffic keys, stamping
+    /// `now_secs` as their `established_at_secs` for [`SecureSession::needs_rekey`]
+    /// to measure from. `is_rekey` moves the keys just superseded (and their
+    /// replay state) into `prev_recv_keys`/`prev_replay` instead of
+    /// discarding them outright, so packets encrypted under them just before
+    /// rollover still decrypt during the brief overlap.
+    fn install_keys(
+        &mut self,
+        now_secs: u64,
+        ck_final: &[u8; 32],
+        role: Role,
+        our_p384_static: Option<&P384SecretKey>,
+        peer_p384_public: Option<&P384PublicKey>,
+        is_rekey: bool,
+    ) {
+        let our_supported = supported_types(our_p384_static.is_some());
+        let peer_supported = supported_types(peer_p384_public.is_some());
+        self.identity_type = identity::negotiate(&our_supported, &peer_supported);
+
+        let p384_secret = if self.identity_type == IdentityType::P384 {
+            match (our_p384_static, peer_p384_public) {
+                (Some(ours), Some(theirs)) => Some(p384_diffie_hellman(ours, theirs)),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        let combined = identity::combine_shared_secrets(Some(ck_final), p384_secret.as_ref());
+
+        let initiator_to_responder: [u8; 32] = combined[0..32].try_into().unwrap();
+        let responder_to_initiator: [u8; 32] = combined[32..64].try_into().unwrap();
+        let (send_key, recv_key) = match role {
+            Role::Initiator => (initiator_to_responder, responder_to_initiator),
+            Role::Responder => (responder_to_initiator, initiator_to_responder),
+        };
+        if is_rekey {
+            self.prev_recv_keys = self.recv_keys.take();
+            self.prev_replay = Some(std::mem::replace(&mut self.replay, ReplayFilter::new()));
+        }
+        self.send_keys = Some(DirectionalKeys { key: send_key, counter: 0, established_at_secs: now_secs, bytes_sent: 0 });
+        self.recv_keys = Some(DirectionalKeys { key: recv_key, counter: 0, established_at_secs: now_secs, bytes_sent: 0 });
+        self.state = HandshakeState::Established;
+        self.rekey = None;
+    }
+
+    /// True once forward-secret traffic keys have been derived in both directions.
+    pub fn is_established(&self) -> bool {
+        matches!(self.state, HandshakeState::Established)
+    }
+
+    /// Returns true if this session is due for a new ephemeral exchange, either
+    /// because the rekey timer has elapsed or the byte budget has been spent.
+    pub fn needs_rekey(&self, now_secs: u64) -> bool {
+        self.send_keys
+            .as_ref()
+            .map(|k| now_secs.saturating_sub(k.established_at_secs) >= REKEY_AFTER_TIME_SECS || k.bytes_sent >= REKEY_AFTER_BYTES)
+            .unwrap_or(false)
+    }
+
+    /// Authenticate, decrypt, and replay-check an inbound data packet, returning
+    /// the plaintext Ethernet frame on success. Falls back to the previous
+    /// generation of keys (if a rekey happened recently) so packets the peer
+    /// encrypted just before rolling over aren't dropped.
+    pub fn decrypt_inbound(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < 8 + 16 {
+            return None;
+        }
+        let counter = u64::from_be_bytes(packet[0..8].try_into().ok()?);
+        // A counter is only recorded in a replay window once the packet has
+        // authenticated under that window's keys, so a packet for the other
+        // key generation (or a forgery) can't burn a counter it doesn't own.
+        if let Some(recv) = self.recv_keys.as_ref() {
+            if let Some(plaintext) = open(recv, packet) {
+                return self.replay.check_and_record(counter).then_some(plaintext);
+            }
+        }
+        if let (Some(prev), Some(prev_replay)) = (self.prev_recv_keys.as_ref(), self.prev_replay.as_mut()) {
+            if let Some(plaintext) = open(prev, packet) {
+                return prev_replay.check_and_record(counter).then_some(plaintext);
+            }
+        }
+        None
+    }
+
+    /// Encrypt an outbound Ethernet frame under the current send key.
+    pub fn encrypt_outbound(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let send = self.send_keys.as_mut()?;
+        send.counter += 1;
+        send.bytes_sent += frame.len() as u64;
+        let counter_bytes = send.counter.to_be_bytes();
+        let cipher = Aes256Gcm::new_from_slice(&send.key).ok()?;
+        let mut nonce_bytes = [0u8; 12];
+        nonce_bytes[4..].copy_from_slice(&counter_bytes);
+        let nonce = Nonce::from_slice(&nonce_bytes);
+        let ciphertext = cipher.encrypt(nonce, Payload { msg: frame, aad: &counter_bytes }).ok()?;
+        let mut out = Vec::with_capacity(8 + ciphertext.len());
+        out.extend_from_slice(&counter_bytes);
+        out.extend_from_slice(&ciphertext);
+        Some(out)
+    }
+}
+
+enum Role {
+    Initiator,
+    Responder,
+}
+
+/// Build message 1 of the handshake (an ephemeral public key, a commitment to
+/// the peer's static key, and our own static key encrypted under `es`),
+/// shared by both the initial handshake ([`SecureSession::new_initiator`]) and
+/// a self-initiated rekey ([`SecureSession::begin_rekey`]). Returns the
+/// ephemeral secret and chaining key the caller needs to process the reply,
+/// plus the message itself.
+fn build_message1(our_static: &StaticSecret, our_p384_static: Option<&P384SecretKey>, their_static: &PublicKey) -> (EphemeralSecret, [u8; 32], Vec<u8>) {
+    let ephemeral_secret = EphemeralSecret::random_from_rng(rand_core::OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral_secret);
+    let commitment = hash_commitment(their_static.as_bytes());
+
+    let es = ephemeral_secret.diffie_hellman(their_static);
+    let (ck_after_es, k1) = mix_key(&[0u8; 32], es.as_bytes());
+
+    let our_static_public = PublicKey::from(our_static);
+    let identity_blob = encode_identity_blob(&our_static_public, our_p384_static);
+    let encrypted_identity = aead_encrypt(&k1, &identity_blob);
+
+    let mut msg = Vec::with_capacity(32 + 32 + encrypted_identity.len());
+    msg.extend_from_slice(ephemeral_public.as_bytes());
+    msg.extend_from_slice(&commitment);
+    msg.extend_from_slice(&encrypted_identity);
+    (ephemeral_secret, ck_after_es, msg)
+}
+
+/// Authenticate and decrypt a data packet under one generation of traffic
+/// keys, shared between the current and previous-generation lookups in
+/// [`SecureSession::decrypt_inbound`].
+fn open(keys: &DirectionalKeys, packet: &[u8]) -> Option<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(&keys.key).ok()?;
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[4..].copy_from_slice(&packet[0..8]);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    cipher.decrypt(nonce, Payload { msg: &packet[8..], aad: &packet[0..8] }).ok()
+}
+
+fn hash_commitment(static_public: &[u8; 32]) -> [u8; 32] {
+    use sha2::Digest;
+    let mut hasher = Sha256::new();
+    hasher.update(static_public);
+    hasher.finalize().into()
+}
+
+/// Encrypt `plaintext` under `key` with a fixed zero nonce. Safe here because
+/// each handshake temp key is used for exactly one encryption, which is the
+/// standard Noise convention for per-message handshake keys.
+fn aead_encrypt(key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+    let cipher = Aes256Gcm::new_from_slice(key).expect("32-byte key");
+    cipher.encrypt(Nonce::from_slice(&[0u8; 12]), plaintext).expect("handshake payloads are short and well under AES-GCM's limits")
+}
+
+fn aead_decrypt(key: &[u8; 32], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(key).ok()?;
+    cipher.decrypt(Nonce::from_slice(&[0u8; 12]), ciphertext).ok()
+}
+
+/// Mix a DH output into the running chaining key, deriving a fresh chaining
+/// key and a temp key for encrypting the next handshake message's payload.
+fn mix_key(chaining_key: &[u8; 32], dh_output: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hk = Hkdf::<Sha256>::new(Some(chaining_key), dh_output);
+    let mut out = [0u8; 64];
+    hk.expand(b"zssp-mix", &mut out).expect("64 bytes is a valid HKDF output length");
+    let mut ck = [0u8; 32];
+    let mut k = [0u8; 32];
+    ck.copy_from_slice(&out[0..32]);
+    k.copy_from_slice(&out[32..64]);
+    (ck, k)
+}
+
+/// Which suites a side supports, in the format [`identity::negotiate`] wants:
+/// legacy (x25519) is always present, since every session has a static
+/// x25519 key; P-384 is added only if this side actually has one.
+fn supported_types(has_p384: bool) -> Vec<IdentityType> {
+    if has_p384 {
+        vec![IdentityType::Legacy, IdentityType::P384]
+    } else {
+        vec![IdentityType::Legacy]
+    }
+}
+
+/// Encode this side's identity as carried inside the (already AEAD-encrypted)
+/// handshake payload: a capabilities byte declaring which suites are on
+/// offer, the x25519 static public key (always present), and, if this side
+/// has one, a P-384 static public key in SEC1 compressed form.
+fn encode_identity_blob(our_static_public: &PublicKey, our_p384_static: Option<&P384SecretKey>) -> Vec<u8> {
+    let mut caps = CAP_LEGACY;
+    let p384_public = our_p384_static.map(|s| s.public_key());
+    if p384_public.is_some() {
+        caps |= CAP_P384;
+    }
+    let mut blob = Vec::with_capacity(1 + 32 + P384_PUBLIC_KEY_LEN);
+    blob.push(caps);
+    blob.extend_from_slice(our_static_public.as_bytes());
+    if let Some(p384_public) = p384_public {
+        blob.extend_from_slice(p384_public.to_sec1_bytes().as_ref());
+    }
+    blob
+}
+
+/// Inverse of [`encode_identity_blob`].
+fn decode_identity_blob(blob: &[u8]) -> Option<(PublicKey, Option<P384PublicKey>)> {
+    if blob.len() < 1 + 32 {
+        return None;
+    }
+    let caps = blob[0];
+    let static_public = PublicKey::from(<[u8; 32]>::try_from(&blob[1..33]).ok()?);
+    let p384_public = if caps & CAP_P384 != 0 {
+        if blob.len() < 1 + 32 + P384_PUBLIC_KEY_LEN {
+            return None;
+        }
+        Some(P384PublicKey::from_sec1_bytes(&blob[33..33 + P384_PUBLIC_KEY_LEN]).ok()?)
+    } else {
+        None
+    };
+    Some((static_public, p384_public))
+}
+
+/// Static-static P-384 ECDH, used in addition to the x25519 agreement when
+/// both sides negotiated [`IdentityType::P384`].
+fn p384_diffie_hellman(our_static: &P384SecretKey, their_public: &P384PublicKey) -> [u8; 48] {
+    let shared = p384::ecdh::diffie_hellman(our_static.to_nonzero_scalar(), their_public.as_affine());
+    let mut out = [0u8; 48];
+    out.copy_from_slice(shared.raw_secret_bytes().as_slice());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_round_trip_establishes_matching_traffic_keys() {
+        let initiator_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_public = PublicKey::from(&responder_static);
+
+        let (mut initiator, msg1) = SecureSession::new_initiator(&initiator_static, None, &responder_public);
+        let mut responder = SecureSession::new_responder(responder_static, None);
+
+        let msg2 = responder.handle_handshake(&msg1, 0).expect("responder replies to message 1");
+        assert!(responder.is_established());
+
+        let ack = initiator.handle_handshake(&msg2, 0);
+        assert!(initiator.is_established());
+        assert!(ack.is_none());
+
+        let frame = b"hello peer";
+        let encrypted = initiator.encrypt_outbound(frame).expect("initiator can encrypt once established");
+        let decrypted = responder.decrypt_inbound(&encrypted).expect("responder can decrypt what the initiator sent");
+        assert_eq!(decrypted, frame);
+
+        let reply = b"hello back";
+        let encrypted_reply = responder.encrypt_outbound(reply).expect("responder can encrypt once established");
+        let decrypted_reply = initiator.decrypt_inbound(&encrypted_reply).expect("initiator can decrypt what the responder sent");
+        assert_eq!(decrypted_reply, reply);
+    }
+
+    #[test]
+    fn replay_filter_rejects_duplicate_and_stale_counters() {
+        let mut filter = ReplayFilter::new();
+        assert!(filter.check_and_record(1));
+        assert!(!filter.check_and_record(1));
+        // Small, sub-64-bit advances must still shift the window so the
+        // previous counter's bit is preserved at its new offset.
+        assert!(filter.check_and_record(2));
+        assert!(!filter.check_and_record(1));
+        assert!(filter.check_and_record(10));
+        assert!(!filter.check_and_record(2));
+        assert!(!filter.check_and_record(10));
+    }
+
+    #[test]
+    fn replay_filter_handles_large_jumps() {
+        let mut filter = ReplayFilter::new();
+        assert!(filter.check_and_record(5));
+        assert!(filter.check_and_record(5 + REPLAY_WINDOW_BITS + 1));
+        // Anything from before the jump is now outside the window.
+        assert!(!filter.check_and_record(5));
+    }
+
+    #[test]
+    fn handshake_negotiates_p384_when_both_sides_support_it() {
+        let initiator_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_public = PublicKey::from(&responder_static);
+        let initiator_p384 = P384SecretKey::random(&mut rand_core::OsRng);
+        let responder_p384 = P384SecretKey::random(&mut rand_core::OsRng);
+
+        let (mut initiator, msg1) = SecureSession::new_initiator(&initiator_static, Some(&initiator_p384), &responder_public);
+        let mut responder = SecureSession::new_responder(responder_static, Some(responder_p384));
+
+        let msg2 = responder.handle_handshake(&msg1, 0).expect("responder replies to message 1");
+        assert!(initiator.handle_handshake(&msg2, 0).is_none());
+
+        assert_eq!(initiator.identity_type(), IdentityType::P384);
+        assert_eq!(responder.identity_type(), IdentityType::P384);
+
+        let frame = b"p384 negotiated";
+        let encrypted = initiator.encrypt_outbound(frame).expect("initiator can encrypt once established");
+        let decrypted = responder.decrypt_inbound(&encrypted).expect("responder can decrypt what the initiator sent");
+        assert_eq!(decrypted, frame);
+    }
+
+    #[test]
+    fn corrupted_message_2_leaves_the_handshake_retryable() {
+        let initiator_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_public = PublicKey::from(&responder_static);
+
+        let (mut initiator, msg1) = SecureSession::new_initiator(&initiator_static, None, &responder_public);
+        let mut responder = SecureSession::new_responder(responder_static, None);
+        let msg2 = responder.handle_handshake(&msg1, 0).expect("responder replies to message 1");
+
+        let mut corrupted = msg2.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(initiator.handle_handshake(&corrupted, 0).is_none());
+        assert!(!initiator.is_established());
+
+        // The real message 2 must still complete the handshake: the
+        // corrupted attempt must not have thrown away the ephemeral secret
+        // and chaining key needed to process a retry.
+        assert!(initiator.handle_handshake(&msg2, 0).is_none());
+        assert!(initiator.is_established());
+    }
+
+    #[test]
+    fn handshake_falls_back_to_legacy_when_peer_lacks_p384() {
+        let initiator_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_public = PublicKey::from(&responder_static);
+        let initiator_p384 = P384SecretKey::random(&mut rand_core::OsRng);
+
+        let (mut initiator, msg1) = SecureSession::new_initiator(&initiator_static, Some(&initiator_p384), &responder_public);
+        let mut responder = SecureSession::new_responder(responder_static, None);
+
+        let msg2 = responder.handle_handshake(&msg1, 0).expect("responder replies to message 1");
+        assert!(initiator.handle_handshake(&msg2, 0).is_none());
+
+        assert_eq!(initiator.identity_type(), IdentityType::Legacy);
+        assert_eq!(responder.identity_type(), IdentityType::Legacy);
+    }
+
+    fn established_pair() -> (SecureSession, SecureSession) {
+        let initiator_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let responder_public = PublicKey::from(&responder_static);
+
+        let (mut initiator, msg1) = SecureSession::new_initiator(&initiator_static, None, &responder_public);
+        let mut responder = SecureSession::new_responder(responder_static, None);
+        let msg2 = responder.handle_handshake(&msg1, 0).expect("responder replies to message 1");
+        assert!(initiator.handle_handshake(&msg2, 0).is_none());
+        (initiator, responder)
+    }
+
+    #[test]
+    fn needs_rekey_trips_after_the_time_budget() {
+        let (initiator, _responder) = established_pair();
+        assert!(!initiator.needs_rekey(REKEY_AFTER_TIME_SECS - 1));
+        assert!(initiator.needs_rekey(REKEY_AFTER_TIME_SECS));
+    }
+
+    #[test]
+    fn needs_rekey_trips_after_the_byte_budget() {
+        let (mut initiator, _responder) = established_pair();
+        initiator.send_keys.as_mut().unwrap().bytes_sent = REKEY_AFTER_BYTES;
+        assert!(initiator.needs_rekey(0));
+    }
+
+    #[test]
+    fn rekey_round_trip_installs_fresh_keys_without_dropping_old_traffic() {
+        let (mut initiator, mut responder) = established_pair();
+
+        let old_frame = b"sent just before rollover";
+        let old_encrypted = initiator.encrypt_outbound(old_frame).expect("old send key still works mid-rekey");
+
+        let rekey_msg1 = initiator.begin_rekey().expect("an established session can start a rekey");
+        assert!(initiator.is_established(), "rekeying must not interrupt in-flight traffic");
+
+        // The peer, who never called begin_rekey itself, accepts the fresh
+        // message 1 against its already-established session instead of
+        // dropping it.
+        let rekey_msg2 = responder.handle_handshake(&rekey_msg1, 1_000).expect("responder answers a peer-initiated rekey");
+        assert!(initiator.handle_handshake(&rekey_msg2, 1_000).is_none());
+
+        // The packet encrypted under the key just superseded still decrypts,
+        // via the overlap window, even though it arrives after the rekey.
+        assert_eq!(responder.decrypt_inbound(&old_encrypted).expect("old key kept around briefly"), old_frame);
+
+        // And the new keys work end to end too.
+        let new_frame = b"sent after rollover";
+        let new_encrypted = initiator.encrypt_outbound(new_frame).expect("new send key installed");
+        assert_eq!(responder.decrypt_inbound(&new_encrypted).expect("responder can decrypt under the new key"), new_frame);
+
+        assert!(!initiator.needs_rekey(1_000), "established_at_secs must be the real rekey completion time, not 0");
+    }
+
+    #[test]
+    fn corrupted_rekey_response_leaves_the_old_session_untouched() {
+        let (mut initiator, mut responder) = established_pair();
+        let frame = b"still flowing on the old key";
+
+        let rekey_msg1 = initiator.begin_rekey().expect("an established session can start a rekey");
+        let rekey_msg2 = responder.handle_handshake(&rekey_msg1, 1_000).expect("responder answers the rekey");
+
+        let mut corrupted = rekey_msg2.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(initiator.handle_handshake(&corrupted, 1_000).is_none());
+        assert!(initiator.is_established(), "a failed rekey must not un-establish the session");
+
+        // Traffic under the still-active old keys keeps working.
+        let encrypted = initiator.encrypt_outbound(frame).expect("old keys are untouched by the failed rekey");
+        assert_eq!(responder.decrypt_inbound(&encrypted).unwrap(), frame);
+
+        // And the real reply can still complete the rekey afterwards.
+        assert!(initiator.handle_handshake(&rekey_msg2, 1_000).is_none());
+    }
+}
@@ -0,0 +1,447 @@
+// (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
+
+//! A BSD-socket-style API layered directly on `Switch`, for embedding ZeroTier
+//! connectivity in applications that can't (or don't want to) bring up a
+//! kernel TUN device.
+//!
+//! Ethernet frames produced by a userspace TCP/IP stack ([`smoltcp`]) are
+//! handed to `Switch` for encryption and delivery via
+//! `Switch::send_unicast_frame` (see `forward_outbound`), and frames `Switch`
+//! would otherwise hand to a tap through `SwitchInterface::local_recv` are
+//! instead queued here and drained into `smoltcp`'s device. A
+//! `ZtSocketStack` owns one `smoltcp` interface per `NetworkId` it's been
+//! asked to join, so an application can open multiple sockets scoped to
+//! different networks through the same `Switch`.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+use smoltcp::iface::{Config, Interface, SocketHandle as SmolHandle, SocketSet};
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpCidr, IpEndpoint};
+
+use crate::vl2::switch::mac_from_bytes;
+use crate::vl2::{MulticastGroup, NetworkId, Switch, SwitchInterface};
+
+/// Mirrors the handful of `SOCK_*` kinds `zt_socket` supports.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum SocketKind {
+    Stream,
+    Dgram,
+}
+
+/// A handle to one open socket, opaque to the caller.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct SocketHandle(u32);
+
+/// Readiness reported by [`ZtSocketStack::poll`] for non-blocking use.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ReadinessEvent {
+    pub socket: SocketHandle,
+    pub readable: bool,
+    pub writable: bool,
+    pub error: bool,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum SocketError {
+    InvalidHandle,
+    WrongSocketKind,
+    AddressInUse,
+    AddressRequired,
+    WouldBlock,
+}
+
+struct OpenSocket {
+    kind: SocketKind,
+    network_id: NetworkId,
+    smol_handle: SmolHandle,
+}
+
+/// A `smoltcp::phy::Device` whose RX queue is fed by `Switch` (frames destined
+/// for this member that aren't claimed by the normal VL2 path) and whose TX
+/// queue is drained by the caller and handed back to `Switch` to encrypt and
+/// send. No actual I/O happens inside the device itself.
+struct SwitchDevice {
+    inbound: VecDeque<Vec<u8>>,
+    outbound: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Device for SwitchDevice {
+    type RxToken<'a> = RxBuf;
+    type TxToken<'a> = TxQueue<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = self.inbound.pop_front()?;
+        Some((RxBuf(frame), TxQueue(&mut self.outbound)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxQueue(&mut self.outbound))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = self.mtu;
+        caps.medium = Medium::Ethernet;
+        caps
+    }
+}
+
+struct RxBuf(Vec<u8>);
+
+impl RxToken for RxBuf {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(mut self, f: F) -> R {
+        f(&mut self.0)
+    }
+}
+
+struct TxQueue<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl<'a> TxToken for TxQueue<'a> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut buf = vec![0u8; len];
+        let r = f(&mut buf);
+        self.0.push_back(buf);
+        r
+    }
+}
+
+/// One `smoltcp` interface, its device, and its socket set, scoped to a
+/// single network.
+struct NetworkStack {
+    interface: Interface,
+    device: SwitchDevice,
+    sockets: SocketSet<'static>,
+    /// Which peer (by 40-bit ZeroTier address) owns each mac this stack has
+    /// learned about on this network, so `forward_outbound` knows who to hand
+    /// an outbound frame to. Populated by `note_peer_mac`.
+    mac_to_peer: HashMap<u64, u64>,
+}
+
+/// Owns the userspace TCP/IP stacks backing every socket the embedding
+/// application has opened, and the glue that moves Ethernet frames between
+/// them and `Switch`.
+#[derive(Default)]
+pub struct ZtSocketStack {
+    networks: HashMap<NetworkId, NetworkStack>,
+    open_sockets: HashMap<SocketHandle, OpenSocket>,
+    next_handle: u32,
+}
+
+impl ZtSocketStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bring up a userspace IP stack for `network_id` using `mac`/`address`
+    /// as this member's Ethernet and IP addresses on it. Must be called
+    /// before opening a socket scoped to that network.
+    pub fn join_network(&mut self, network_id: NetworkId, mac: [u8; 6], address: IpCidr) {
+        let mut device = SwitchDevice { inbound: VecDeque::new(), outbound: VecDeque::new(), mtu: 1500 };
+        let config = Config::new(HardwareAddress::Ethernet(EthernetAddress(mac)));
+        let mut interface = Interface::new(config, &mut device, Instant::from_millis(0));
+        interface.update_ip_addrs(|addrs| {
+            addrs.push(address).ok();
+        });
+        self.networks.insert(network_id, NetworkStack { interface, device, sockets: SocketSet::new(Vec::new()), mac_to_peer: HashMap::new() });
+    }
+
+    /// Feed a frame `Switch` received for this member (and didn't otherwise
+    /// claim) into the IP stack for `network_id`.
+    pub fn inject_inbound(&mut self, network_id: NetworkId, ethernet_frame: Vec<u8>) {
+        if let Some(stack) = self.networks.get_mut(&network_id) {
+            stack.device.inbound.push_back(ethernet_frame);
+        }
+    }
+
+    /// Reconstruct the Ethernet frame behind a `SwitchInterface::local_recv`
+    /// callback and inject it, so a host's `local_recv` implementation can
+    /// forward straight into this stack with no frame bookkeeping of its own.
+    pub fn handle_local_recv(&mut self, network_id: NetworkId, source_mac: u64, dest_mac: u64, ethertype: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(14 + payload.len());
+        frame.extend_from_slice(&dest_mac.to_be_bytes()[2..8]);
+        frame.extend_from_slice(&source_mac.to_be_bytes()[2..8]);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.inject_inbound(network_id, frame);
+    }
+
+    /// Drain Ethernet frames the IP stack wants sent for `network_id`, for
+    /// the caller to hand to `Switch` for encryption and delivery.
+    pub fn drain_outbound(&mut self, network_id: NetworkId) -> Vec<Vec<u8>> {
+        self.networks.get_mut(&network_id).map(|s| s.device.outbound.drain(..).collect()).unwrap_or_default()
+    }
+
+    /// Record that `mac` (as seen on `network_id`) is owned by `peer_address`,
+    /// so `forward_outbound` knows which session to encrypt outbound frames
+    /// addressed to it under. The host application learns this the same way
+    /// it learns any other peer's address on the network (e.g. controller
+    /// membership data or having seen traffic from it).
+    pub fn note_peer_mac(&mut self, network_id: NetworkId, mac: u64, peer_address: u64) {
+        if let Some(stack) = self.networks.get_mut(&network_id) {
+            stack.mac_to_peer.insert(mac, peer_address);
+        }
+    }
+
+    /// Drain every frame `network_id`'s IP stack wants sent and, for each
+    /// whose destination mac has a known owner (via `note_peer_mac`), hand it
+    /// to `switch` to be encrypted and delivered over that peer's session.
+    /// Frames to a destination mac with no known owner yet are dropped, the
+    /// same way a real NIC drops a frame before ARP/NDP resolves a route.
+    pub fn forward_outbound<I: SwitchInterface>(&mut self, network_id: NetworkId, switch: &Switch<I>) {
+        let Some(mac_to_peer) = self.networks.get(&network_id).map(|s| s.mac_to_peer.clone()) else {
+            return;
+        };
+        for frame in self.drain_outbound(network_id) {
+            if frame.len() < 14 {
+                continue;
+            }
+            if let Some(peer_address) = mac_to_peer.get(&mac_from_bytes(&frame[0..6])) {
+                // Re-frame into the VL2 layout the receiving `Switch` decodes:
+                // network ID, source MAC, destination MAC, Ethertype, payload.
+                let mut vl2_frame = Vec::with_capacity(8 + frame.len());
+                vl2_frame.extend_from_slice(&network_id.0.to_be_bytes());
+                vl2_frame.extend_from_slice(&frame[6..12]);
+                vl2_frame.extend_from_slice(&frame[0..6]);
+                vl2_frame.extend_from_slice(&frame[12..]);
+                switch.send_unicast_frame(*peer_address, &vl2_frame);
+            }
+        }
+    }
+
+    /// Advance every joined network's IP stack by one tick: processes queued
+    /// inbound frames, runs protocol timers, and queues any resulting
+    /// outbound frames for `drain_outbound`.
+    pub fn poll(&mut self, now_millis: i64) {
+        let timestamp = Instant::from_millis(now_millis);
+        for stack in self.networks.values_mut() {
+            stack.interface.poll(timestamp, &mut stack.device, &mut stack.sockets);
+        }
+    }
+
+    /// Open a new socket of `kind` scoped to `network_id`. `join_network`
+    /// must have been called for that network first.
+    pub fn socket(&mut self, network_id: NetworkId, kind: SocketKind) -> Option<SocketHandle> {
+        let stack = self.networks.get_mut(&network_id)?;
+        let smol_handle = match kind {
+            SocketKind::Stream => {
+                let rx = tcp::SocketBuffer::new(vec![0; 65536]);
+                let tx = tcp::SocketBuffer::new(vec![0; 65536]);
+                stack.sockets.add(tcp::Socket::new(rx, tx))
+            }
+            SocketKind::Dgram => {
+                let rx = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 64], vec![0; 65536]);
+                let tx = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 64], vec![0; 65536]);
+                stack.sockets.add(udp::Socket::new(rx, tx))
+            }
+        };
+        let handle = SocketHandle(self.next_handle);
+        self.next_handle += 1;
+        self.open_sockets.insert(handle, OpenSocket { kind, network_id, smol_handle });
+        Some(handle)
+    }
+
+    /// Bind a datagram socket to a local endpoint, or put a stream socket
+    /// into the listening state (mirroring `bind` + `listen` being distinct
+    /// BSD calls collapsed here since `smoltcp`'s TCP socket models both as
+    /// a single `listen`).
+    pub fn bind(&mut self, handle: SocketHandle, local: IpEndpoint) -> Result<(), SocketError> {
+        let open = self.open_sockets.get(&handle).ok_or(SocketError::InvalidHandle)?;
+        let stack = self.networks.get_mut(&open.network_id).ok_or(SocketError::InvalidHandle)?;
+        match open.kind {
+            SocketKind::Dgram => {
+                stack.sockets.get_mut::<udp::Socket>(open.smol_handle).bind(local).map_err(|_| SocketError::AddressInUse)
+            }
+            SocketKind::Stream => {
+                stack.sockets.get_mut::<tcp::Socket>(open.smol_handle).listen(local).map_err(|_| SocketError::AddressInUse)
+            }
+        }
+    }
+
+    /// Connect a stream socket to a remote endpoint.
+    pub fn connect(&mut self, handle: SocketHandle, remote: IpEndpoint, local_port: u16) -> Result<(), SocketError> {
+        let open = self.open_sockets.get(&handle).ok_or(SocketError::InvalidHandle)?;
+        if open.kind != SocketKind::Stream {
+            return Err(SocketError::WrongSocketKind);
+        }
+        let stack = self.networks.get_mut(&open.network_id).ok_or(SocketError::InvalidHandle)?;
+        let (socket, cx) = stack.sockets.get_mut::<tcp::Socket>(open.smol_handle).split_with(stack.interface.context());
+        socket.connect(cx, remote, local_port).map_err(|_| SocketError::WouldBlock)
+    }
+
+    /// Report whether a listening stream socket has picked up a connection.
+    /// `smoltcp`'s TCP socket carries that connection itself, so it is used
+    /// through this same handle; no new handle is returned, and the socket
+    /// does not listen for further connections while it holds one.
+    pub fn accept(&mut self, handle: SocketHandle) -> Result<bool, SocketError> {
+        let open = self.open_sockets.get(&handle).ok_or(SocketError::InvalidHandle)?;
+        if open.kind != SocketKind::Stream {
+            return Err(SocketError::WrongSocketKind);
+        }
+        let stack = self.networks.get(&open.network_id).ok_or(SocketError::InvalidHandle)?;
+        Ok(stack.sockets.get::<tcp::Socket>(open.smol_handle).is_active())
+    }
+
+    pub fn send(&mut self, handle: SocketHandle, data: &[u8], to: Option<IpEndpoint>) -> Result<usize, SocketError> {
+        let open = self.open_sockets.get(&handle).ok_or(SocketError::InvalidHandle)?;
+        let stack = self.networks.get_mut(&open.network_id).ok_or(SocketError::InvalidHandle)?;
+        match open.kind {
+            SocketKind::Stream => stack.sockets.get_mut::<tcp::Socket>(open.smol_handle).send_slice(data).map_err(|_| SocketError::WouldBlock),
+            SocketKind::Dgram => {
+                let to = to.ok_or(SocketError::AddressRequired)?;
+                stack
+                    .sockets
+                    .get_mut::<udp::Socket>(open.smol_handle)
+                    .send_slice(data, to)
+                    .map(|_| data.len())
+                    .map_err(|_| SocketError::WouldBlock)
+            }
+        }
+    }
+
+    pub fn recv(&mut self, handle: SocketHandle, buf: &mut [u8]) -> Result<usize, SocketError> {
+        let open = self.open_sockets.get(&handle).ok_or(SocketError::InvalidHandle)?;
+        let stack = self.networks.get_mut(&open.network_id).ok_or(SocketError::InvalidHandle)?;
+        match open.kind {
+            SocketKind::Stream => stack.sockets.get_mut::<tcp::Socket>(open.smol_handle).recv_slice(buf).map_err(|_| SocketError::WouldBlock),
+            SocketKind::Dgram => stack.sockets.get_mut::<udp::Socket>(open.smol_handle).recv_slice(buf).map(|(n, _)| n).map_err(|_| SocketError::WouldBlock),
+        }
+    }
+
+    /// Join a multicast group on the IP stack backing `network_id`'s sockets
+    /// so locally-destined multicast frames are accepted instead of dropped.
+    /// The caller is still responsible for telling `Switch` (via its own
+    /// multicast APIs) that this member now wants `group`, since that's what
+    /// drives propagation over the network.
+    pub fn join_multicast(&mut self, network_id: NetworkId, group: MulticastGroup) -> Result<(), SocketError> {
+        let stack = self.networks.get_mut(&network_id).ok_or(SocketError::InvalidHandle)?;
+        let addr = EthernetAddress((group.mac.to_be_bytes()[2..8]).try_into().unwrap());
+        stack.interface.join_multicast_group(&mut stack.device, addr, Instant::from_millis(0)).map(|_| ()).map_err(|_| SocketError::WouldBlock)
+    }
+
+    /// Non-blocking readiness check across every open socket, for an event
+    /// loop driving `recv`/`send` without blocking.
+    pub fn poll_events(&self) -> Vec<ReadinessEvent> {
+        let mut events = Vec::new();
+        for (handle, open) in &self.open_sockets {
+            let Some(stack) = self.networks.get(&open.network_id) else { continue };
+            let (readable, writable, error) = match open.kind {
+                SocketKind::Stream => {
+                    let s = stack.sockets.get::<tcp::Socket>(open.smol_handle);
+                    (s.can_recv(), s.can_send(), !s.is_open() && s.state() == tcp::State::Closed)
+                }
+                SocketKind::Dgram => {
+                    let s = stack.sockets.get::<udp::Socket>(open.smol_handle);
+                    (s.can_recv(), s.can_send(), false)
+                }
+            };
+            events.push(ReadinessEvent { socket: *handle, readable, writable, error });
+        }
+        events
+    }
+}
+
+/// Convert a `std::net` address/port pair into the `smoltcp` endpoint type
+/// `bind`/`connect` expect.
+pub fn ip_endpoint(addr: IpAddr, port: u16) -> IpEndpoint {
+    IpEndpoint::new(addr.into(), port)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use smoltcp::wire::Ipv4Cidr;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::*;
+    use crate::vl2::identity::{Identity, IdentityType};
+
+    #[derive(Default)]
+    struct FakeInterface {
+        wire_sent: Mutex<Vec<(u64, Vec<u8>)>>,
+    }
+
+    impl SwitchInterface for FakeInterface {
+        fn wire_send(&self, peer_address: u64, data: &[u8]) {
+            self.wire_sent.lock().unwrap().push((peer_address, data.to_vec()));
+        }
+        fn local_recv(&self, _network_id: NetworkId, _source_mac: u64, _dest_mac: u64, _ethertype: u16, _data: &[u8]) {}
+        fn multicast_subscriptions_changed(&self, _network_id: NetworkId, _groups: &[MulticastGroup]) {}
+        fn multicast_gather(&self, _network_id: NetworkId, _group: MulticastGroup, _limit: u32) {}
+        fn peer_identity_type(&self, _peer_address: u64, _identity_type: IdentityType) {}
+    }
+
+    /// Brute-force a proof-of-work nonce for `static_secret`'s public key, the
+    /// same way a real legacy identity is minted, so tests can hand `Switch`
+    /// an `Identity` instead of a bare key.
+    fn legacy_identity(static_secret: &StaticSecret) -> Identity {
+        let public = PublicKey::from(static_secret);
+        (0u64..).find_map(|nonce| Identity::new_legacy(public, nonce)).expect("a passing nonce exists within a small search")
+    }
+
+    fn handshake(a: &Switch<FakeInterface>, a_address: u64, b: &Switch<FakeInterface>, b_identity: &Identity) -> u64 {
+        let b_address = b_identity.address().0;
+        assert!(a.open_session(b_identity));
+        let (_, msg1) = a.interface.wire_sent.lock().unwrap().pop().unwrap();
+        b.receive(a_address, 0, &msg1);
+        let (_, msg2) = b.interface.wire_sent.lock().unwrap().pop().unwrap();
+        a.receive(b_address, 0, &msg2);
+        b_address
+    }
+
+    #[test]
+    fn handle_local_recv_reconstructs_the_ethernet_frame() {
+        let mut stack = ZtSocketStack::new();
+        let network_id = NetworkId(1);
+        stack.join_network(network_id, [2, 0, 0, 0, 0, 1], IpCidr::Ipv4(Ipv4Cidr::new(smoltcp::wire::Ipv4Address::new(10, 0, 0, 1), 24)));
+        stack.handle_local_recv(network_id, 0x0200_0000_0002, 0x0200_0000_0001, 0x0800, b"payload");
+
+        let frame = &stack.networks.get(&network_id).unwrap().device.inbound[0];
+        assert_eq!(&frame[0..6], &[2, 0, 0, 0, 0, 1]);
+        assert_eq!(&frame[6..12], &[2, 0, 0, 0, 0, 2]);
+        assert_eq!(&frame[12..14], &0x0800u16.to_be_bytes());
+        assert_eq!(&frame[14..], b"payload");
+    }
+
+    #[test]
+    fn forward_outbound_sends_to_the_learned_peer_and_drops_unknown_destinations() {
+        let a_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let b_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let b_identity = legacy_identity(&b_static);
+        let a_switch = Switch::new(FakeInterface::default(), a_static, None);
+        let b_switch = Switch::new(FakeInterface::default(), b_static, None);
+        let b_address = handshake(&a_switch, 1, &b_switch, &b_identity);
+
+        let mut stack = ZtSocketStack::new();
+        let network_id = NetworkId(1);
+        stack.join_network(network_id, [2, 0, 0, 0, 0, 1], IpCidr::Ipv4(Ipv4Cidr::new(smoltcp::wire::Ipv4Address::new(10, 0, 0, 1), 24)));
+
+        let known_dest_mac = 0x0200_0000_0002u64;
+        let mut known_frame = Vec::new();
+        known_frame.extend_from_slice(&known_dest_mac.to_be_bytes()[2..]);
+        known_frame.extend_from_slice(&[2, 0, 0, 0, 0, 1]);
+        known_frame.extend_from_slice(&0x0800u16.to_be_bytes());
+        known_frame.extend_from_slice(b"hi");
+
+        let mut unknown_frame = known_frame.clone();
+        unknown_frame[0] = 0xff; // a destination mac with no learned peer
+
+        {
+            let net = stack.networks.get_mut(&network_id).unwrap();
+            net.device.outbound.push_back(known_frame);
+            net.device.outbound.push_back(unknown_frame);
+        }
+        stack.note_peer_mac(network_id, known_dest_mac, b_address);
+
+        stack.forward_outbound(network_id, &a_switch);
+
+        let sent = a_switch.interface.wire_sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, b_address);
+    }
+}
@@ -0,0 +1,629 @@
+// (c) 2020-2022 ZeroTier, Inc. -- currently propritery pending actual release and licensing. See LICENSE.md.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use std::net::IpAddr;
+
+use p384::SecretKey as P384SecretKey;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::vl2::identity::{Identity, IdentityType};
+use crate::vl2::igmp::{self, MembershipEvent};
+use crate::vl2::multicast::MulticastPropagator;
+use crate::vl2::nameservice::{NameService, Zone};
+use crate::vl2::session::SecureSession;
+use crate::vl2::{MulticastGroup, NetworkId};
+
+/// Default TTL (in seconds) advertised on synthesized name-service answers.
+const DNS_ANSWER_TTL_SECS: u32 = 60;
+
+/// How long a snooped membership is kept alive with no renewal before it's
+/// dropped, mirroring the IGMP/MLD query-response interval.
+const MEMBERSHIP_TIMEOUT_SECS: u64 = 260;
+
+/// The first byte of every packet `Switch` receives selects how the rest of
+/// the packet is interpreted.
+pub(crate) mod packet_type {
+    pub(crate) const HANDSHAKE: u8 = 0x01;
+    pub(crate) const DATA: u8 = 0x02;
+    /// Announces that the sender wants to receive a `(NetworkId, MulticastGroup)`.
+    pub(crate) const MULTICAST_LIKE: u8 = 0x03;
+    /// Asks the recipient (normally a root or the controller) for more known
+    /// members of a group than the sender has on hand.
+    pub(crate) const MULTICAST_GATHER: u8 = 0x04;
+    /// Carries a list of members learned in response to a `MULTICAST_GATHER`.
+    pub(crate) const MULTICAST_GATHER_RESULT: u8 = 0x05;
+    /// A multicast/broadcast Ethernet frame being replicated to group members.
+    pub(crate) const MULTICAST_FRAME: u8 = 0x06;
+}
+
+fn read_group(body: &[u8]) -> Option<(NetworkId, MulticastGroup, usize)> {
+    if body.len() < 18 {
+        return None;
+    }
+    let network_id = NetworkId(u64::from_be_bytes(body[0..8].try_into().unwrap()));
+    let mac = mac_from_bytes(&body[8..14]);
+    let adi = u32::from_be_bytes(body[14..18].try_into().unwrap());
+    Some((network_id, MulticastGroup::new(mac, adi), 18))
+}
+
+fn write_group(out: &mut Vec<u8>, network_id: NetworkId, group: MulticastGroup) {
+    out.extend_from_slice(&network_id.0.to_be_bytes());
+    out.extend_from_slice(&group.mac.to_be_bytes()[2..]);
+    out.extend_from_slice(&group.adi.to_be_bytes());
+}
+
+/// Callbacks that the host application implements so `Switch` can reach the
+/// outside world: the physical/UDP wire, and the local virtual Ethernet tap.
+pub trait SwitchInterface: Sync + Send {
+    /// Send a raw (already encrypted, if applicable) packet to a peer by its
+    /// 40-bit ZeroTier address.
+    fn wire_send(&self, peer_address: u64, data: &[u8]);
+
+    /// Deliver a decoded VL2 Ethernet frame to the local network stack/tap.
+    fn local_recv(&self, network_id: NetworkId, source_mac: u64, dest_mac: u64, ethertype: u16, data: &[u8]);
+
+    /// Called whenever the set of multicast groups this member wants to
+    /// receive on `network_id` changes, so the controller/roots can be told.
+    fn multicast_subscriptions_changed(&self, network_id: NetworkId, groups: &[MulticastGroup]);
+
+    /// Called when local knowledge of a group's members falls short of
+    /// `limit`, so the host application can ask upstream roots/the controller
+    /// to gather more members on `Switch`'s behalf.
+    fn multicast_gather(&self, network_id: NetworkId, group: MulticastGroup, limit: u32);
+
+    /// Called once a peer's handshake has negotiated which identity suite
+    /// (legacy x25519/Ed25519 or P384) the session with it is using.
+    fn peer_identity_type(&self, peer_address: u64, identity_type: IdentityType);
+}
+
+/// The VL2 switch: terminates secure sessions with peers and demultiplexes
+/// the Ethernet frames carried inside them.
+pub struct Switch<I: SwitchInterface> {
+    pub(crate) interface: I,
+    /// This node's own static key agreement secret, used to both initiate and
+    /// respond to handshakes.
+    our_static: StaticSecret,
+    /// This node's own P-384 static secret, if it has one. Offered during the
+    /// handshake alongside `our_static` so sessions with peers that also
+    /// support P-384 negotiate up to [`IdentityType::P384`].
+    our_p384_static: Option<P384SecretKey>,
+    sessions: Mutex<Vec<(u64, SecureSession)>>,
+    /// Snooped multicast memberships, keyed by network and group, with the
+    /// timestamp (in seconds) after which they expire without renewal.
+    memberships: Mutex<HashMap<NetworkId, HashMap<MulticastGroup, u64>>>,
+    /// Known remote members and per-network dedup state for the
+    /// replicate-and-gather multicast propagation algorithm.
+    propagator: Mutex<MulticastPropagator>,
+    /// Controller-pushed zones, runtime overrides, and negative cache for
+    /// in-network name resolution.
+    names: Mutex<NameService>,
+}
+
+impl<I: SwitchInterface> Switch<I> {
+    /// `our_static` is this node's own x25519 static secret, used both to
+    /// respond to inbound handshakes and to initiate new ones via
+    /// [`Switch::open_session`]. `our_p384_static`, if this node also has a
+    /// P-384 identity, is offered during every handshake so sessions with
+    /// peers that support it too negotiate up to [`IdentityType::P384`].
+    pub fn new(interface: I, our_static: StaticSecret, our_p384_static: Option<P384SecretKey>) -> Self {
+        Self {
+            interface,
+            our_static,
+            our_p384_static,
+            sessions: Mutex::new(Vec::new()),
+            memberships: Mutex::new(HashMap::new()),
+            propagator: Mutex::new(MulticastPropagator::default()),
+            names: Mutex::new(NameService::default()),
+        }
+    }
+
+    /// Start a handshake with the peer described by `peer_identity`, replacing
+    /// any existing session with that peer. The session is addressed at
+    /// `peer_identity.address()` rather than a separately supplied `u64`, so
+    /// the address a peer is reached at can never drift from the public key
+    /// actually being authenticated in the handshake. Returns `false` (and
+    /// starts nothing) for a [`Identity::P384`] peer, since that variant
+    /// carries no x25519 key and `SecureSession` always negotiates at least
+    /// the legacy suite.
+    ///
+    /// Sends the first handshake message via `SwitchInterface::wire_send`.
+    /// The host is told the negotiated identity type through
+    /// `SwitchInterface::peer_identity_type` once the peer's reply completes
+    /// the handshake (see [`Switch::receive`]).
+    pub fn open_session(&self, peer_identity: &Identity) -> bool {
+        let Identity::Legacy { x25519_public, .. } = peer_identity else {
+            return false;
+        };
+        let peer_address = peer_identity.address().0;
+        let (session, msg1) = SecureSession::new_initiator(&self.our_static, self.our_p384_static.as_ref(), x25519_public);
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|(a, _)| *a != peer_address);
+        sessions.push((peer_address, session));
+        drop(sessions);
+        self.interface.wire_send(peer_address, &msg1);
+        true
+    }
+
+    /// Check every session's rekey timer/byte-budget and start a fresh
+    /// ephemeral exchange for any that are due, without disturbing the
+    /// traffic keys already in use until the peer replies. The host
+    /// application should call this periodically (e.g. once a second);
+    /// `now_secs` is the same monotonic clock reading passed to
+    /// [`Switch::receive`].
+    pub fn rekey_due_sessions(&self, now_secs: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let due: Vec<(u64, Vec<u8>)> = sessions
+            .iter_mut()
+            .filter(|(_, session)| session.needs_rekey(now_secs))
+            .filter_map(|(peer_address, session)| session.begin_rekey().map(|msg| (*peer_address, msg)))
+            .collect();
+        drop(sessions);
+        for (peer_address, msg) in due {
+            self.interface.wire_send(peer_address, &msg);
+        }
+    }
+
+    /// Encrypt `ethernet_frame` under the established session with
+    /// `peer_address` and send it as a unicast `DATA` packet. Returns `false`
+    /// (and sends nothing) if there is no session with that peer yet, or the
+    /// handshake with it hasn't completed. `ethernet_frame` is sent as given;
+    /// the receiving `Switch` decodes it in the VL2 layout described on
+    /// `decode_ethernet_frame` (network ID, source MAC, destination MAC,
+    /// Ethertype, payload).
+    pub fn send_unicast_frame(&self, peer_address: u64, ethernet_frame: &[u8]) -> bool {
+        self.send_encrypted(peer_address, packet_type::DATA, ethernet_frame)
+    }
+
+    /// Encrypt `body` under the established session with `peer_address` and
+    /// send it to the wire tagged with `type_byte`. All traffic this module
+    /// emits -- unicast data as well as multicast control and data -- goes
+    /// through a session this way, since each peer's session has its own
+    /// traffic keys and a plaintext packet built once can't simply be
+    /// reused across peers. Returns `false` (and sends nothing) if there is
+    /// no session with that peer yet, or the handshake with it hasn't
+    /// completed.
+    fn send_encrypted(&self, peer_address: u64, type_byte: u8, body: &[u8]) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some((_, session)) = sessions.iter_mut().find(|(a, _)| *a == peer_address) else {
+            return false;
+        };
+        let Some(ciphertext) = session.encrypt_outbound(body) else {
+            return false;
+        };
+        drop(sessions);
+        let mut packet = Vec::with_capacity(1 + ciphertext.len());
+        packet.push(type_byte);
+        packet.extend_from_slice(&ciphertext);
+        self.interface.wire_send(peer_address, &packet);
+        true
+    }
+
+    /// Authenticate and decrypt a packet body received from `peer_address`
+    /// under that peer's session. Returns `None` (dropping the packet) if
+    /// there is no established session with that peer.
+    fn decrypt_from(&self, peer_address: u64, body: &[u8]) -> Option<Vec<u8>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let (_, session) = sessions.iter_mut().find(|(a, _)| *a == peer_address)?;
+        session.decrypt_inbound(body)
+    }
+
+    /// Install (or replace) the signed zone for `network_id`, as distributed
+    /// by that network's controller. Clears any stale negative-cache entries
+    /// for the network so names added by the new zone resolve immediately.
+    pub fn push_zone(&self, network_id: NetworkId, zone: Zone) {
+        self.names.lock().unwrap().push_zone(network_id, zone);
+    }
+
+    /// Add or replace a single name on top of (and ahead of) the zone pushed
+    /// for `network_id`, for runtime overrides that don't need a full zone
+    /// update.
+    pub fn set_name_override(&self, network_id: NetworkId, name: String, addresses: Vec<IpAddr>) {
+        self.names.lock().unwrap().set_override(network_id, name, addresses);
+    }
+
+    /// Remove a runtime override previously set with `set_name_override`,
+    /// falling back to whatever the zone (if any) says about that name.
+    pub fn clear_name_override(&self, network_id: NetworkId, name: &str) {
+        self.names.lock().unwrap().clear_override(network_id, name);
+    }
+
+    /// Send an Ethernet multicast/broadcast frame to at most `limit` of the
+    /// closest/most-recently-active known members of `group` on `network_id`.
+    /// If fewer than `limit` members are known locally, a gather request is
+    /// raised via `SwitchInterface::multicast_gather` to learn more for next
+    /// time, but delivery still proceeds to whoever is already known.
+    pub fn send_multicast_frame(&self, network_id: NetworkId, group: MulticastGroup, now_secs: u64, frame_id: u64, ethernet_frame: &[u8], limit: usize) {
+        let (recipients, have_enough) = self.propagator.lock().unwrap().select_recipients(network_id, group, now_secs, limit);
+        if !have_enough {
+            self.interface.multicast_gather(network_id, group, limit as u32);
+        }
+        let mut body = Vec::with_capacity(18 + 8 + ethernet_frame.len());
+        write_group(&mut body, network_id, group);
+        body.extend_from_slice(&frame_id.to_be_bytes());
+        body.extend_from_slice(ethernet_frame);
+        // Each recipient has its own session keys, so the body has to be
+        // encrypted once per recipient rather than broadcast as one packet.
+        for recipient in recipients {
+            self.send_encrypted(recipient, packet_type::MULTICAST_FRAME, &body);
+        }
+    }
+
+    /// Announce this node's own interest in `group` to a peer (typically a
+    /// root), so it is included in future gather responses for other members.
+    pub fn send_multicast_like(&self, peer_address: u64, network_id: NetworkId, group: MulticastGroup) {
+        let mut body = Vec::with_capacity(18);
+        write_group(&mut body, network_id, group);
+        self.send_encrypted(peer_address, packet_type::MULTICAST_LIKE, &body);
+    }
+
+    /// Called by the host application whenever a packet arrives from `peer_address`.
+    ///
+    /// Dispatches on the leading packet type byte: handshake packets are fed to
+    /// the peer's (possibly new) `SecureSession` -- including a fresh handshake
+    /// message 1 from a peer that already has an established session with us,
+    /// which `SecureSession::handle_handshake` treats as that peer rekeying
+    /// rather than a message to drop -- data packets are authenticated,
+    /// decrypted, and checked against the session's replay window before the
+    /// decoded frame is handed to `SwitchInterface::local_recv`. Packets that fail
+    /// AEAD authentication or replay validation are silently dropped. `now_secs`
+    /// is a monotonic clock reading used to age out multicast memberships and,
+    /// for handshake packets, stamped as the new keys' establishment time.
+    pub fn receive(&self, peer_address: u64, now_secs: u64, data: &[u8]) {
+        let (type_byte, body) = match data.split_first() {
+            Some(v) => v,
+            None => return,
+        };
+        match *type_byte {
+            packet_type::HANDSHAKE => {
+                let mut sessions = self.sessions.lock().unwrap();
+                // A session already exists for a rekey (the peer's or our
+                // own, via `rekey_due_sessions`); only a brand new peer needs
+                // a fresh responder session.
+                let is_new = sessions.iter().all(|(a, _)| *a != peer_address);
+                if is_new {
+                    sessions.push((peer_address, SecureSession::new_responder(self.our_static.clone(), self.our_p384_static.clone())));
+                }
+                let session = &mut sessions.iter_mut().find(|(a, _)| *a == peer_address).unwrap().1;
+                let was_established = session.is_established();
+                let identity_type_before = session.identity_type();
+                let reply = session.handle_handshake(body, now_secs);
+                let identity_type_changed = session.is_established() && (!was_established || session.identity_type() != identity_type_before);
+                let identity_type = session.identity_type();
+                drop(sessions);
+                // The suite a fresh session (responder or initiator) starts
+                // with is only a placeholder until its handshake completes, so
+                // tell the host application as soon as the real suite is
+                // known, and again if a rekey negotiates a different one.
+                if identity_type_changed {
+                    self.interface.peer_identity_type(peer_address, identity_type);
+                }
+                if let Some(reply) = reply {
+                    self.interface.wire_send(peer_address, &reply);
+                }
+            }
+            packet_type::DATA => {
+                let mut sessions = self.sessions.lock().unwrap();
+                if let Some((_, session)) = sessions.iter_mut().find(|(a, _)| *a == peer_address) {
+                    if let Some(frame) = session.decrypt_inbound(body) {
+                        self.decode_ethernet_frame(now_secs, &frame);
+                    }
+                }
+            }
+            packet_type::MULTICAST_LIKE => {
+                let Some(plaintext) = self.decrypt_from(peer_address, body) else {
+                    return;
+                };
+                if let Some((network_id, group, _)) = read_group(&plaintext) {
+                    self.propagator.lock().unwrap().add_member(network_id, group, peer_address, now_secs);
+                }
+            }
+            packet_type::MULTICAST_GATHER => {
+                let Some(plaintext) = self.decrypt_from(peer_address, body) else {
+                    return;
+                };
+                if let Some((network_id, group, consumed)) = read_group(&plaintext) {
+                    if plaintext.len() >= consumed + 4 {
+                        let limit = u32::from_be_bytes(plaintext[consumed..consumed + 4].try_into().unwrap()) as usize;
+                        let (members, _) = self.propagator.lock().unwrap().select_recipients(network_id, group, now_secs, limit);
+                        let mut reply = Vec::with_capacity(18 + 2 + members.len() * 8);
+                        write_group(&mut reply, network_id, group);
+                        reply.extend_from_slice(&(members.len() as u16).to_be_bytes());
+                        for m in &members {
+                            reply.extend_from_slice(&m.to_be_bytes());
+                        }
+                        self.send_encrypted(peer_address, packet_type::MULTICAST_GATHER_RESULT, &reply);
+                    }
+                }
+            }
+            packet_type::MULTICAST_GATHER_RESULT => {
+                let Some(plaintext) = self.decrypt_from(peer_address, body) else {
+                    return;
+                };
+                if let Some((network_id, group, consumed)) = read_group(&plaintext) {
+                    if plaintext.len() >= consumed + 2 {
+                        let count = u16::from_be_bytes(plaintext[consumed..consumed + 2].try_into().unwrap()) as usize;
+                        let addresses = plaintext[consumed + 2..].chunks_exact(8).take(count);
+                        let mut propagator = self.propagator.lock().unwrap();
+                        for a in addresses {
+                            propagator.add_member(network_id, group, u64::from_be_bytes(a.try_into().unwrap()), now_secs);
+                        }
+                    }
+                }
+            }
+            packet_type::MULTICAST_FRAME => {
+                let Some(plaintext) = self.decrypt_from(peer_address, body) else {
+                    return;
+                };
+                if let Some((network_id, group, consumed)) = read_group(&plaintext) {
+                    if plaintext.len() >= consumed + 8 {
+                        let frame_id = u64::from_be_bytes(plaintext[consumed..consumed + 8].try_into().unwrap());
+                        let ethernet_frame = &plaintext[consumed + 8..];
+                        let is_new = self.propagator.lock().unwrap().check_and_record_frame(network_id, frame_id);
+                        if is_new {
+                            self.decode_ethernet_frame(now_secs, ethernet_frame);
+                            // Re-flood to whatever other members we already know about; the
+                            // gather path above is what grows that knowledge over time. Each
+                            // recipient gets its own encryption pass since a re-sent
+                            // ciphertext can't be re-used across sessions.
+                            let (recipients, _) = self.propagator.lock().unwrap().select_recipients(network_id, group, now_secs, usize::MAX);
+                            for recipient in recipients.into_iter().filter(|a| *a != peer_address) {
+                                self.send_encrypted(recipient, packet_type::MULTICAST_FRAME, &plaintext);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Expected wire layout of a decrypted VL2 frame: 8-byte network ID, 6-byte
+    /// source MAC, 6-byte destination MAC, 2-byte Ethertype, then the payload.
+    fn decode_ethernet_frame(&self, now_secs: u64, frame: &[u8]) {
+        if frame.len() < 8 + 6 + 6 + 2 {
+            return;
+        }
+        let network_id = NetworkId(u64::from_be_bytes(frame[0..8].try_into().unwrap()));
+        let source_mac = mac_from_bytes(&frame[8..14]);
+        let dest_mac = mac_from_bytes(&frame[14..20]);
+        let ethertype = u16::from_be_bytes([frame[20], frame[21]]);
+        let payload = &frame[22..];
+
+        if ethertype == 0x0800 {
+            if let Some(answer) = self.try_answer_dns(network_id, now_secs, payload) {
+                self.interface.local_recv(network_id, dest_mac, source_mac, ethertype, &answer);
+                return;
+            }
+        }
+
+        self.snoop_membership(network_id, now_secs, ethertype, payload);
+        self.interface.local_recv(network_id, source_mac, dest_mac, ethertype, payload);
+    }
+
+    /// Answer a snooped DNS query for a name under this network's search
+    /// domain (or a PTR query) directly from the cached zone/overrides,
+    /// without involving a real resolver. Returns the IPv4+UDP+DNS reply
+    /// packet for every such query, an NXDOMAIN reply when the name or
+    /// address is unknown; `None` for any other packet.
+    fn try_answer_dns(&self, network_id: NetworkId, now_secs: u64, ipv4_payload: &[u8]) -> Option<Vec<u8>> {
+        use crate::vl2::nameservice::{DnsAnswer, Lookup};
+        let mut names = self.names.lock().unwrap();
+        let search_domain = names.search_domain(network_id)?.to_string();
+        crate::vl2::nameservice::snoop_and_answer(ipv4_payload, &search_domain, DNS_ANSWER_TTL_SECS, |lookup| match lookup {
+            Lookup::Forward(name) => names.resolve(network_id, now_secs, name).map(DnsAnswer::Addresses),
+            Lookup::Ptr(addr) => names.resolve_ptr(network_id, addr).map(|s| DnsAnswer::Name(s.to_string())),
+        })
+    }
+
+    /// Parse any IGMP/MLD membership message in `payload` and update this
+    /// network's subscription table, notifying `SwitchInterface` if it changed.
+    fn snoop_membership(&self, network_id: NetworkId, now_secs: u64, ethertype: u16, payload: &[u8]) {
+        let events = igmp::snoop(ethertype, payload);
+        if events.is_empty() {
+            return;
+        }
+        let is_ipv6 = ethertype == 0x86dd;
+        let mut memberships = self.memberships.lock().unwrap();
+        let network_groups = memberships.entry(network_id).or_default();
+        let mut changed = false;
+        for event in events {
+            match event {
+                MembershipEvent::Join { group_ip } => {
+                    let (mac, adi) = igmp::group_ip_to_mac_adi(group_ip, is_ipv6);
+                    let group = MulticastGroup::new(mac, adi);
+                    changed |= !network_groups.contains_key(&group);
+                    network_groups.insert(group, now_secs + MEMBERSHIP_TIMEOUT_SECS);
+                }
+                MembershipEvent::Leave { group_ip } => {
+                    let (mac, adi) = igmp::group_ip_to_mac_adi(group_ip, is_ipv6);
+                    let group = MulticastGroup::new(mac, adi);
+                    changed |= network_groups.remove(&group).is_some();
+                }
+            }
+        }
+        let before = network_groups.len();
+        network_groups.retain(|_, expiry| *expiry > now_secs);
+        changed |= network_groups.len() != before;
+        if changed {
+            let groups: Vec<MulticastGroup> = network_groups.keys().copied().collect();
+            self.interface.multicast_subscriptions_changed(network_id, &groups);
+        }
+    }
+}
+
+pub(crate) fn mac_from_bytes(b: &[u8]) -> u64 {
+    ((b[0] as u64) << 40) | ((b[1] as u64) << 32) | ((b[2] as u64) << 24) | ((b[3] as u64) << 16) | ((b[4] as u64) << 8) | (b[5] as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Captures everything a `Switch` hands back to its `SwitchInterface` so
+    /// tests can drive two `Switch`es' wire traffic by hand and inspect what
+    /// each delivered locally.
+    #[derive(Default)]
+    struct FakeInterface {
+        wire_sent: Mutex<Vec<(u64, Vec<u8>)>>,
+        local_recv: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl SwitchInterface for FakeInterface {
+        fn wire_send(&self, peer_address: u64, data: &[u8]) {
+            self.wire_sent.lock().unwrap().push((peer_address, data.to_vec()));
+        }
+        fn local_recv(&self, _network_id: NetworkId, _source_mac: u64, _dest_mac: u64, _ethertype: u16, data: &[u8]) {
+            self.local_recv.lock().unwrap().push(data.to_vec());
+        }
+        fn multicast_subscriptions_changed(&self, _network_id: NetworkId, _groups: &[MulticastGroup]) {}
+        fn multicast_gather(&self, _network_id: NetworkId, _group: MulticastGroup, _limit: u32) {}
+        fn peer_identity_type(&self, _peer_address: u64, _identity_type: IdentityType) {}
+    }
+
+    /// Brute-force a proof-of-work nonce for `static_secret`'s public key, the
+    /// same way a real legacy identity is minted, so tests can hand `Switch`
+    /// an `Identity` instead of a bare key.
+    fn legacy_identity(static_secret: &StaticSecret) -> Identity {
+        let public = PublicKey::from(static_secret);
+        (0u64..).find_map(|nonce| Identity::new_legacy(public, nonce)).expect("a passing nonce exists within a small search")
+    }
+
+    /// Complete a handshake between `a` (as initiator towards `b_identity`)
+    /// and `b` (as responder to `a_address`), feeding `a`'s message 1 to `b`
+    /// and `b`'s reply back to `a`. Returns the address `b_identity` derives
+    /// to, which is also where `a` ends up addressing `b`.
+    fn handshake(a: &Switch<FakeInterface>, a_address: u64, b: &Switch<FakeInterface>, b_identity: &Identity) -> u64 {
+        let b_address = b_identity.address().0;
+        assert!(a.open_session(b_identity));
+        let (peer, msg1) = a.interface.wire_sent.lock().unwrap().pop().unwrap();
+        assert_eq!(peer, b_address);
+        b.receive(a_address, 0, &msg1);
+        let (peer, msg2) = b.interface.wire_sent.lock().unwrap().pop().unwrap();
+        assert_eq!(peer, a_address);
+        a.receive(b_address, 0, &msg2);
+        b_address
+    }
+
+    /// Wrap `payload` in the decrypted VL2 frame layout `decode_ethernet_frame`
+    /// expects, with an ARP Ethertype so neither DNS answering nor IGMP/MLD
+    /// snooping claims it.
+    fn vl2_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(22 + payload.len());
+        frame.extend_from_slice(&NetworkId(1).0.to_be_bytes());
+        frame.extend_from_slice(&[2, 0, 0, 0, 0, 1]); // source MAC
+        frame.extend_from_slice(&[2, 0, 0, 0, 0, 2]); // destination MAC
+        frame.extend_from_slice(&0x0806u16.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn send_unicast_frame_round_trips_through_encryption() {
+        let a_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let b_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let b_identity = legacy_identity(&b_static);
+
+        let a = Switch::new(FakeInterface::default(), a_static, None);
+        let b = Switch::new(FakeInterface::default(), b_static, None);
+        let b_address = handshake(&a, 1, &b, &b_identity);
+
+        let frame = b"an ethernet frame";
+        assert!(a.send_unicast_frame(b_address, &vl2_frame(frame)));
+        let (peer, packet) = a.interface.wire_sent.lock().unwrap().pop().unwrap();
+        assert_eq!(peer, b_address);
+        b.receive(1, 0, &packet);
+        assert_eq!(b.interface.local_recv.lock().unwrap().pop().unwrap(), frame);
+    }
+
+    #[test]
+    fn open_session_rejects_a_p384_only_identity() {
+        // SecureSession always negotiates at least the legacy suite, so a
+        // peer described only by a P384 identity (no x25519 key) can't be
+        // addressed this way.
+        let a_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let a = Switch::new(FakeInterface::default(), a_static, None);
+        let p384_identity = Identity::new_p384(p384::SecretKey::random(&mut rand_core::OsRng).public_key(), *p384::ecdsa::SigningKey::random(&mut rand_core::OsRng).verifying_key());
+        assert!(!a.open_session(&p384_identity));
+        assert!(a.interface.wire_sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn multicast_like_is_encrypted_and_rejected_without_a_session() {
+        let a_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let b_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let b_identity = legacy_identity(&b_static);
+
+        let a = Switch::new(FakeInterface::default(), a_static, None);
+        let b = Switch::new(FakeInterface::default(), b_static, None);
+        let b_address = handshake(&a, 1, &b, &b_identity);
+
+        let network_id = NetworkId(42);
+        let group = MulticastGroup::new(0x01_00_5e_01_02_03, 0);
+        a.send_multicast_like(b_address, network_id, group);
+        let (_, packet) = a.interface.wire_sent.lock().unwrap().pop().unwrap();
+        // A cleartext MULTICAST_LIKE payload would parse as a group header;
+        // an eavesdropper intercepting the real (encrypted) packet must not
+        // be able to read the group out of it.
+        assert_ne!(&packet[1..], &{
+            let mut plain = Vec::new();
+            write_group(&mut plain, network_id, group);
+            plain
+        }[..]);
+
+        // The recipient, which has no session with this address, must drop
+        // the packet instead of trusting an unauthenticated group-join.
+        let c_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let c = Switch::new(FakeInterface::default(), c_static, None);
+        c.receive(1, 0, &packet);
+        assert!(c.propagator.lock().unwrap().select_recipients(network_id, group, 0, 1).0.is_empty());
+
+        // The real recipient, which does have a session, accepts it.
+        b.receive(1, 0, &packet);
+        assert_eq!(b.propagator.lock().unwrap().select_recipients(network_id, group, 0, 1).0, vec![1]);
+    }
+
+    #[test]
+    fn rekey_due_sessions_refreshes_keys_without_interrupting_traffic() {
+        let a_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let b_static = StaticSecret::random_from_rng(rand_core::OsRng);
+        let b_identity = legacy_identity(&b_static);
+
+        let a = Switch::new(FakeInterface::default(), a_static, None);
+        let b = Switch::new(FakeInterface::default(), b_static, None);
+        let b_address = handshake(&a, 1, &b, &b_identity);
+
+        // Nothing due yet: a fresh session's clock/byte budget hasn't elapsed.
+        a.rekey_due_sessions(0);
+        assert!(a.interface.wire_sent.lock().unwrap().is_empty());
+
+        // Force the time budget: drive the rekey handshake the same way
+        // `handshake` drives the initial one, then confirm traffic encrypted
+        // under the old keys right up until rollover still decrypts.
+        let frame = b"still flows across the rekey";
+        assert!(a.send_unicast_frame(b_address, &vl2_frame(frame)));
+        let (_, packet) = a.interface.wire_sent.lock().unwrap().pop().unwrap();
+
+        let far_future = 365 * 24 * 60 * 60;
+        a.rekey_due_sessions(far_future);
+        let (peer, msg1) = a.interface.wire_sent.lock().unwrap().pop().unwrap();
+        assert_eq!(peer, b_address);
+        b.receive(1, far_future, &msg1);
+        let (peer, msg2) = b.interface.wire_sent.lock().unwrap().pop().unwrap();
+        assert_eq!(peer, 1);
+        a.receive(b_address, far_future, &msg2);
+
+        // The packet sent under the pre-rekey keys, delivered only now, must
+        // still be accepted via the session's overlap window.
+        b.receive(1, far_future, &packet);
+        assert_eq!(b.interface.local_recv.lock().unwrap().pop().unwrap(), frame);
+
+        // And fresh traffic under the new keys works too.
+        let frame2 = b"and after the rekey completes";
+        assert!(a.send_unicast_frame(b_address, &vl2_frame(frame2)));
+        let (_, packet2) = a.interface.wire_sent.lock().unwrap().pop().unwrap();
+        b.receive(1, far_future, &packet2);
+        assert_eq!(b.interface.local_recv.lock().unwrap().pop().unwrap(), frame2);
+    }
+}